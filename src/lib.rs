@@ -0,0 +1,3346 @@
+//! bfstk is a brainfuck interpreter. The library holds the lexer, parser,
+//! optimizer passes and evaluator, which the bfstk command line tool is built
+//! on.
+
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+use snafu::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::result;
+
+pub type Result<T> = result::Result<T, BFE>;
+
+/// FRAME_SIZE is the largest payload written in a single output frame.
+pub const FRAME_SIZE: usize = 4096;
+
+/// OUTPUT_BUFFER_SIZE is how much program output is buffered before it is
+/// written to stdout.
+pub const OUTPUT_BUFFER_SIZE: usize = 8192;
+
+/// Newlines selects the line ending that newlines written by the program are
+/// translated to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Newlines {
+    Lf,
+    Crlf,
+}
+
+impl std::str::FromStr for Newlines {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(Newlines::Lf),
+            "crlf" => Ok(Newlines::Crlf),
+            _ => Err(format!("unknown line ending '{}', expected 'lf' or 'crlf'", s)),
+        }
+    }
+}
+
+/// LoopPolarity selects which cell value keeps a loop running.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LoopPolarity {
+    // NonZero is standard brainfuck: a loop runs while its cell is nonzero.
+    #[default]
+    NonZero,
+    // Zero inverts the condition so that a loop runs while its cell is zero,
+    // as some dialects do. Standard programs run under this polarity will
+    // usually loop forever, because loops are written to exit on zero.
+    Zero,
+}
+
+impl std::str::FromStr for LoopPolarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "nonzero" => Ok(LoopPolarity::NonZero),
+            "zero" => Ok(LoopPolarity::Zero),
+            _ => Err(format!("unknown loop polarity '{}', expected 'zero' or 'nonzero'", s)),
+        }
+    }
+}
+
+/// Eof selects what the Read instruction does to its cell once input runs out.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Eof {
+    // Unchanged leaves the cell as it was, the most portable convention.
+    #[default]
+    Unchanged,
+    // Zero sets the cell to 0.
+    Zero,
+    // Max sets the cell to -1, which is 255 for 8-bit cells.
+    Max,
+    // Error stops the program with an error.
+    Error,
+}
+
+impl std::str::FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(Eof::Unchanged),
+            "0" => Ok(Eof::Zero),
+            "255" | "-1" => Ok(Eof::Max),
+            "error" => Ok(Eof::Error),
+            _ => Err(format!(
+                "unknown EOF mode '{}', expected 'unchanged', '0', '255' or 'error'",
+                s
+            )),
+        }
+    }
+}
+
+/// Cell is the type cell values are passed around as, which is wide enough
+/// for every cell width. Values are kept within the range of the chosen
+/// CellBits, and the Tape stores each cell at that width.
+pub type Cell = u32;
+
+/// CellBits selects how wide the cells of the tape are.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CellBits {
+    // Eight is standard brainfuck.
+    #[default]
+    Eight,
+    Sixteen,
+    ThirtyTwo,
+}
+
+impl CellBits {
+    /// max returns the largest value a cell can hold.
+    pub fn max(self) -> Cell {
+        match self {
+            CellBits::Eight => return u8::MAX.into(),
+            CellBits::Sixteen => return u16::MAX.into(),
+            CellBits::ThirtyTwo => return u32::MAX,
+        }
+    }
+
+    /// bytes returns how many bytes a cell takes up.
+    pub fn bytes(self) -> usize {
+        match self {
+            CellBits::Eight => return 1,
+            CellBits::Sixteen => return 2,
+            CellBits::ThirtyTwo => return 4,
+        }
+    }
+}
+
+/// each_width runs the same expression on the cells of a Tape, whichever
+/// width they are stored at.
+macro_rules! each_width {
+    ($tape:expr, $cells:ident => $body:expr) => {
+        match $tape {
+            Tape::Eight($cells) => $body,
+            Tape::Sixteen($cells) => $body,
+            Tape::ThirtyTwo($cells) => $body,
+        }
+    };
+}
+
+/// Tape holds the allocated cells of a tape, each stored at its CellBits
+/// width, from the leftmost cell to the rightmost.
+#[derive(Debug, Clone)]
+pub enum Tape {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+    ThirtyTwo(Vec<u32>),
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        return Tape::Eight(vec![]);
+    }
+}
+
+impl Tape {
+    /// new returns a tape of len zeroed cells of the given width.
+    pub fn new(bits: CellBits, len: usize) -> Tape {
+        match bits {
+            CellBits::Eight => return Tape::Eight(vec![0; len]),
+            CellBits::Sixteen => return Tape::Sixteen(vec![0; len]),
+            CellBits::ThirtyTwo => return Tape::ThirtyTwo(vec![0; len]),
+        }
+    }
+
+    /// bits returns the width the cells are stored at.
+    pub fn bits(&self) -> CellBits {
+        match self {
+            Tape::Eight(_) => return CellBits::Eight,
+            Tape::Sixteen(_) => return CellBits::Sixteen,
+            Tape::ThirtyTwo(_) => return CellBits::ThirtyTwo,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return each_width!(self, cells => cells.len());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// capacity returns how many cells the tape can hold before it has to be
+    /// reallocated.
+    pub fn capacity(&self) -> usize {
+        return each_width!(self, cells => cells.capacity());
+    }
+
+    /// get returns the cell at index i, if the tape holds that many.
+    pub fn get(&self, i: usize) -> Option<Cell> {
+        return each_width!(self, cells => cells.get(i).map(|&c| Cell::from(c)));
+    }
+
+    /// set stores the value in the cell at index i, which must already be
+    /// within the tape and the range of its width.
+    pub fn set(&mut self, i: usize, value: Cell) {
+        each_width!(self, cells => cells[i] = value as _);
+    }
+
+    /// resize grows or shrinks the tape to len cells, adding zeros on the right.
+    pub fn resize(&mut self, len: usize) {
+        each_width!(self, cells => cells.resize(len, 0));
+    }
+
+    /// prepend adds n zeroed cells on the left, shifting every cell over.
+    pub fn prepend(&mut self, n: usize) {
+        each_width!(self, cells => drop(cells.splice(0..0, std::iter::repeat_n(0, n))));
+    }
+
+    /// clear zeroes the cells in the given range of indexes.
+    pub fn clear(&mut self, range: std::ops::RangeInclusive<usize>) {
+        each_width!(self, cells => cells[range].fill(0));
+    }
+
+    /// iter iterates over the cells from the leftmost to the rightmost.
+    pub fn iter(&self) -> impl Iterator<Item = Cell> + '_ {
+        return (0..self.len()).map(|i| self.get(i).unwrap_or(0));
+    }
+
+    /// next_zero returns the index of the first zero cell at or after i.
+    fn next_zero(&self, i: usize) -> Option<usize> {
+        return each_width!(self, cells => cells[i..].iter().position(|&c| c == 0).map(|j| i + j));
+    }
+
+    /// last_zero returns the index of the last zero cell at or before i.
+    fn last_zero(&self, i: usize) -> Option<usize> {
+        return each_width!(self, cells => cells[..=i].iter().rposition(|&c| c == 0));
+    }
+}
+
+impl std::str::FromStr for CellBits {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellBits::Eight),
+            "16" => Ok(CellBits::Sixteen),
+            "32" => Ok(CellBits::ThirtyTwo),
+            _ => Err(format!("unknown cell width '{}', expected '8', '16' or '32'", s)),
+        }
+    }
+}
+
+/// Overflow selects what happens when a cell is incremented past the largest
+/// value it can hold or decremented past 0.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    // Wrap is standard brainfuck: cells wrap around, modulo 256 for 8-bit
+    // cells.
+    #[default]
+    Wrap,
+    // Saturate clamps cells to 0 and the largest cell value, as some
+    // interpreters do.
+    Saturate,
+    // Trap stops the program with an error instead.
+    Trap,
+}
+
+impl Overflow {
+    /// apply adds delta to the cell at pointer according to the overflow mode,
+    /// for cells that hold at most max.
+    fn apply(self, pointer: i16, value: Cell, delta: i32, max: Cell) -> Result<Cell> {
+        let sum = i64::from(value) + i64::from(delta);
+        match self {
+            Overflow::Wrap => return Ok(sum.rem_euclid(i64::from(max) + 1) as Cell),
+            Overflow::Saturate => return Ok(sum.clamp(0, max.into()) as Cell),
+            Overflow::Trap => {
+                ensure!(
+                    (0..=i64::from(max)).contains(&sum),
+                    CellOverflowSnafu { pointer, value, delta }
+                );
+                return Ok(sum as Cell);
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(format!(
+                "unknown overflow mode '{}', expected 'wrap', 'saturate' or 'trap'",
+                s
+            )),
+        }
+    }
+}
+
+/// TraceFormat selects how each executed instruction is logged to stderr when
+/// tracing is enabled.
+#[derive(Debug, Clone, Copy)]
+pub enum TraceFormat {
+    // Text emits one human-readable line per step.
+    Text,
+    // Json emits one JSON object per step (JSON Lines), with the fields
+    // step, node, pointer, before and after.
+    Json,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("unknown trace format '{}', expected 'text' or 'json'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum BFE {
+    #[snafu(display("{source}"))]
+    Args { source: pico_args::Error },
+    #[snafu(display("cannot write file '{filename}'"))]
+    FileWrite { source: std::io::Error, filename: String },
+    #[snafu(display("'{filename}' is {size} bytes, over the limit of {limit}"))]
+    ProgramTooLarge {
+        filename: String,
+        size: usize,
+        limit: usize,
+    },
+    #[snafu(display("{reason}"))]
+    InvalidFlags { reason: String },
+    #[snafu(display("cannot load file '{filename}'"))]
+    FileLoad { source: std::io::Error, filename: String },
+    // Fetch is only raised when built with the 'http' feature
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    #[snafu(display("cannot fetch '{url}': {source}"))]
+    Fetch { source: std::io::Error, url: String },
+    #[snafu(display("cannot fetch '{url}': {reason}"))]
+    FetchUrl { url: String, reason: String },
+    #[snafu(display(
+        "tape out of bounds at cell {pointer}{}: {reason}, with the tape holding cells {low} to {high}",
+        at_source(*location)
+    ))]
+    TapeOutOfBounds {
+        pointer: i16,
+        low: i32,
+        high: i32,
+        reason: String,
+        location: Option<(usize, usize)>,
+    },
+    #[snafu(display("line {line} of input file list '{filename}' must hold a program and an input path"))]
+    InvalidInputList { filename: String, line: usize },
+    #[snafu(display("'{filename}' is not deterministic: {reason}"))]
+    Nondeterministic { filename: String, reason: String },
+    #[snafu(display("found {count} problem(s) in '{filename}'"))]
+    CheckFailed { filename: String, count: usize },
+    #[snafu(display("'{filename}' has unmatched brackets: {unmatched}"))]
+    UnmatchedBrackets { filename: String, unmatched: String },
+    #[snafu(display("unmatched '[' at line {line}, col {col}"))]
+    UnclosedJump { line: usize, col: usize },
+    #[snafu(display("unmatched ']' at line {line}, col {col}"))]
+    UnexpectedClose { line: usize, col: usize },
+    #[snafu(display("cannot read the program's input from stdin or its input file: {source}"))]
+    ReadInput { source: std::io::Error },
+    #[snafu(display("final cell of '{filename}' holds {actual}, expected {expected}"))]
+    FinalCellMismatch {
+        filename: String,
+        expected: Cell,
+        actual: Cell,
+    },
+    #[snafu(display("invalid run length at offset {offset}: {reason}"))]
+    InvalidRunLength { offset: usize, reason: String },
+    #[snafu(display("loop at offset {offset} exceeded the limit of {limit} iterations"))]
+    LoopLimitExceeded { offset: usize, limit: u64 },
+    #[snafu(display("program exceeded the limit of {steps} steps"))]
+    StepLimitExceeded { steps: usize },
+    #[snafu(display("'{filename}' timed out after {seconds} seconds"))]
+    Timeout { filename: String, seconds: f64 },
+    #[snafu(display("cell {pointer} overflowed: {value} {delta:+}"))]
+    CellOverflow { pointer: i16, value: Cell, delta: i32 },
+    #[snafu(display("cannot write output: {source}"))]
+    WriteOutput { source: std::io::Error },
+    #[snafu(display("BUG! internal invariant violated: {reason}"))]
+    InvariantViolation { reason: String },
+}
+
+/// at_source describes where in the source an error happened, given its line
+/// and column if known.
+fn at_source(location: Option<(usize, usize)>) -> String {
+    return match location {
+        Some((line, col)) => format!(" (line {}, col {})", line, col),
+        None => String::new(),
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    // offset is the byte offset of the token in the source.
+    pub offset: usize,
+    // line and col are the 1-based position of the token in the source, with
+    // col counted in characters.
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Clone)]
+pub enum TokenKind {
+    Comment(char),
+
+    DecrementByte, // -
+    IncrementByte, // +
+
+    MoveRight, // >
+    MoveLeft,  // <
+
+    Input,  // ,
+    Output, // .
+
+    JumpRight, // [ // also jump-if-zero
+    JumpLeft,  // ] // also jump-if-nonzero
+
+    Flush, // & // only with --flush-extension
+}
+
+impl TokenKind {
+    /// name is the name of the token's variant.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            TokenKind::Comment(_) => "Comment",
+            TokenKind::DecrementByte => "DecrementByte",
+            TokenKind::IncrementByte => "IncrementByte",
+            TokenKind::MoveRight => "MoveRight",
+            TokenKind::MoveLeft => "MoveLeft",
+            TokenKind::Input => "Input",
+            TokenKind::Output => "Output",
+            TokenKind::JumpRight => "JumpRight",
+            TokenKind::JumpLeft => "JumpLeft",
+            TokenKind::Flush => "Flush",
+        };
+    }
+
+    /// symbol returns the source character the token was lexed from.
+    pub fn symbol(&self) -> char {
+        return match self {
+            TokenKind::Comment(c) => *c,
+            TokenKind::DecrementByte => '-',
+            TokenKind::IncrementByte => '+',
+            TokenKind::MoveRight => '>',
+            TokenKind::MoveLeft => '<',
+            TokenKind::Input => ',',
+            TokenKind::Output => '.',
+            TokenKind::JumpRight => '[',
+            TokenKind::JumpLeft => ']',
+            TokenKind::Flush => '&',
+        };
+    }
+}
+
+/// lex scans through the input and coverts each character into a token. No
+/// transformation happens at this step.
+pub fn lex(content: String) -> Result<Vec<Token>> {
+    let mut toks = Vec::with_capacity(content.len());
+    lex_into(&mut toks, &content, 0, (1, 1));
+    return Ok(toks);
+}
+
+/// relex patches the tokens lexed from an earlier version of a source after
+/// an edit that left every byte before `edit` unchanged, for editors that
+/// lex again on every keystroke. The tokens before the edit are kept, and the
+/// rest of the new content is lexed from there.
+pub fn relex(mut tokens: Vec<Token>, content: &str, edit: usize) -> Result<Vec<Token>> {
+    tokens.retain(|t| t.offset < edit);
+    let (start, pos) = match tokens.last() {
+        Some(t) if t.kind.symbol() == '\n' => (t.offset + 1, (t.line + 1, 1)),
+        Some(t) => (t.offset + t.kind.symbol().len_utf8(), (t.line, t.col + 1)),
+        None => (0, (1, 1)),
+    };
+    ensure!(
+        content.is_char_boundary(start),
+        InvariantViolationSnafu {
+            reason: format!("expecting the content before offset {} to be unchanged", edit),
+        }
+    );
+    lex_into(&mut tokens, &content[start..], start, pos);
+    return Ok(tokens);
+}
+
+/// reparse patches the tokens after an edit as relex does and parses the
+/// result, returning the patched tokens to pass back in on the next edit.
+pub fn reparse(tokens: Vec<Token>, content: &str, edit: usize) -> Result<(Vec<Token>, Vec<Node>)> {
+    let tokens = relex(tokens, content, edit)?;
+    let nodes = parse(tokens.clone(), false)?;
+    return Ok((tokens, nodes));
+}
+
+/// lex_into lexes content that starts at the given byte offset, line and
+/// column of the source, appending its tokens to toks.
+fn lex_into(toks: &mut Vec<Token>, content: &str, start: usize, (mut line, mut col): (usize, usize)) {
+    for (offset, ch) in content.char_indices() {
+        let kind = match ch {
+            '-' => TokenKind::DecrementByte,
+            '+' => TokenKind::IncrementByte,
+            '>' => TokenKind::MoveRight,
+            '<' => TokenKind::MoveLeft,
+            ',' => TokenKind::Input,
+            '.' => TokenKind::Output,
+            '[' => TokenKind::JumpRight,
+            ']' => TokenKind::JumpLeft,
+            c => TokenKind::Comment(c),
+        };
+
+        toks.push(Token {
+            kind,
+            offset: start + offset,
+            line,
+            col,
+        });
+        match ch {
+            '\n' => (line, col) = (line + 1, 1),
+            _ => col += 1,
+        }
+    }
+}
+
+/// Node represents a node that could have been combined from one or more tokens.
+#[derive(Debug, Clone)]
+pub enum Node {
+    // Comment is a comment string, which in brainfuck could be anything that
+    // isn't an instruction.
+    Comment(String),
+    // Delta represents a series of one or more increments and/or decrements
+    // in a row. By convention, net positive increments results in a positive
+    // delta value, and net positive decrements results in a negative delta value.
+    // Net zeros are elided during parsing.
+    Delta(i32),
+    // Move represents a series of one or more cell moves left or right. By
+    // convention, moves right have positive values, while moves left have
+    // negative values.
+    Move(i16),
+    // Read is an instruction to read one u8 character from the state's input,
+    // which is stdin unless the state was given another reader or buffer.
+    Read,
+    // Write is an instruction to write one u8 character to the state's
+    // output, which is stdout unless the state was given another writer.
+    Write,
+    // Flush writes out any output that is still buffered.
+    Flush,
+    // Block is a list of parsed nodes from between a JumpRight and JumpLeft
+    // pair of tokens, along with the source offset of the JumpRight.
+    Block(usize, Vec<Node>),
+    // SetZero is a clear loop, '[-]' or '[+]', which sets the cell to zero
+    // in one step instead of looping.
+    SetZero,
+    // MulAdd is a multiply loop such as '[->+>++<<]', which adds the cell
+    // times a factor to the cells at each offset, and then clears the cell.
+    MulAdd(Vec<(i16, i32)>),
+    // Scan is a scan loop such as '[>]' or '[<<]', which moves the pointer by
+    // its step until it lands on a zero cell.
+    Scan(i16),
+    // OffsetDeltas is a run of moves and deltas such as '>+>++<<', which adds
+    // each delta to the cell at its offset and then moves the pointer by the
+    // net move, all in one step.
+    OffsetDeltas(Vec<(i16, i32)>, i16),
+    // ClearRange is a run of clear loops on neighbouring cells such as
+    // '[-]>[-]>[-]', which zeroes the cells from the first offset to the second
+    // in one step without moving the pointer.
+    ClearRange(i16, i16),
+}
+
+/// parse runs through the list of tokens, coalescing similar tokens in a row
+/// if they are safe to combine, and emits a list of parsed nodes. When explain
+/// is set, every coalescing decision is logged to stderr.
+pub fn parse(tokens: Vec<Token>, explain: bool) -> Result<Vec<Node>> {
+    return Ok(parse_positioned(tokens, explain)?.0);
+}
+
+/// Position is the source offset a node starts at, along with the positions
+/// of the nodes in its body for a Block, so that a tree of positions mirrors
+/// the tree of nodes.
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub offset: usize,
+    pub body: Vec<Position>,
+}
+
+/// parse_positioned parses tokens like parse, also returning the position of
+/// every node.
+pub fn parse_positioned(tokens: Vec<Token>, explain: bool) -> Result<(Vec<Node>, Vec<Position>)> {
+    let mut spans: Vec<Vec<Node>> = vec![vec![]];
+    let mut positions: Vec<Vec<Position>> = vec![vec![]];
+    // opens holds the offset, line and column of every unclosed JumpRight
+    let mut opens: Vec<(usize, usize, usize)> = vec![];
+    let mut span = spans.last_mut().context(InvariantViolationSnafu {
+        reason: "expecting 'spans' stack to not be empty",
+    })?;
+
+    for token in tokens {
+        let (kind, len) = (token.kind.clone(), span.len());
+        match token.kind {
+            // a comment can be combined into the same comment node, when the
+            // previous token was also a comment
+            TokenKind::Comment(b) => match span.last_mut() {
+                Some(Node::Comment(a)) => a.push(b),
+                _ => span.push(Node::Comment(b.to_string())),
+            },
+
+            // a decrement or an increment can be combined when the previous
+            // node was a delta, which happens when the previous token was also
+            // either a decrement or an increment, or when the tokens in between
+            // cancelled out
+            TokenKind::DecrementByte => match span.last_mut() {
+                Some(Node::Delta(a)) => {
+                    *a -= 1;
+                }
+                _ => {
+                    span.push(Node::Delta(-1));
+                }
+            },
+            TokenKind::IncrementByte => match span.last_mut() {
+                Some(Node::Delta(a)) => {
+                    *a += 1;
+                }
+                _ => {
+                    span.push(Node::Delta(1));
+                }
+            },
+
+            // moves right or left can be combined when the previous node
+            // was a move, in the same way as deltas
+            TokenKind::MoveRight => match span.last_mut() {
+                Some(Node::Move(a)) => {
+                    *a += 1;
+                }
+                _ => {
+                    span.push(Node::Move(1));
+                }
+            },
+            TokenKind::MoveLeft => match span.last_mut() {
+                Some(Node::Move(a)) => {
+                    *a -= 1;
+                }
+                _ => {
+                    span.push(Node::Move(-1));
+                }
+            },
+
+            TokenKind::Input => span.push(Node::Read),
+            TokenKind::Output => span.push(Node::Write),
+            TokenKind::Flush => span.push(Node::Flush),
+
+            TokenKind::JumpRight => {
+                opens.push((token.offset, token.line, token.col));
+                spans.push(vec![]);
+                positions.push(vec![]);
+                span = spans.last_mut().context(InvariantViolationSnafu {
+                    reason: "expecting 'spans' stack to not be empty when encountering JumpRight token",
+                })?;
+            }
+            TokenKind::JumpLeft => {
+                let Some((offset, ..)) = opens.pop() else {
+                    return UnexpectedCloseSnafu {
+                        line: token.line,
+                        col: token.col,
+                    }
+                    .fail();
+                };
+                let prev = spans.pop().context(InvariantViolationSnafu {
+                    reason: "expecting 'spans' stack to hold a span for every unclosed JumpRight token",
+                })?;
+                span = spans.last_mut().context(InvariantViolationSnafu {
+                    reason: "expecting 'spans' stack to hold the parent of a closed block",
+                })?;
+                span.push(Node::Block(offset, prev));
+                let body = positions.pop().unwrap_or_default();
+                if let Some(parent) = positions.last_mut() {
+                    parent.push(Position { offset, body });
+                }
+            }
+        }
+        elide_net_zero(span);
+
+        // a token either started a node, dropped one by cancelling it out, or
+        // merged into the last one, which keeps its position
+        let position = positions.last_mut().context(InvariantViolationSnafu {
+            reason: "expecting 'positions' stack to mirror the 'spans' stack",
+        })?;
+        if span.len() > position.len() {
+            position.push(Position {
+                offset: token.offset,
+                body: vec![],
+            });
+        } else if span.len() < position.len() {
+            position.pop();
+        }
+
+        if explain {
+            explain_parse(&kind, token.offset, len, span);
+        }
+    }
+
+    if let Some(&(_, line, col)) = opens.last() {
+        return UnclosedJumpSnafu { line, col }.fail();
+    }
+
+    let f = spans.pop().context(InvariantViolationSnafu {
+        reason: "expecting 'spans' stack to not be empty at end of parsing",
+    })?;
+    return Ok((f, positions.swap_remove(0)));
+}
+
+/// elide_net_zero drops the last node of a span if it is a Delta or Move that
+/// cancelled out to zero, such as from '+-' or '><', so that it never runs.
+fn elide_net_zero(span: &mut Vec<Node>) {
+    if matches!(span.last(), Some(Node::Delta(0) | Node::Move(0))) {
+        span.pop();
+    }
+}
+
+/// concat_programs appends program b to program a, coalescing the nodes where
+/// they meet the way parse would have if the sources had been one: a trailing
+/// Delta or Move of a merges with a leading one of b, and merges that cancel
+/// out are dropped, which may let the next pair merge in turn. Blocks keep the
+/// source offsets of the program they came from.
+pub fn concat_programs(mut a: Vec<Node>, b: Vec<Node>) -> Vec<Node> {
+    for node in b {
+        match (a.last_mut(), node) {
+            (Some(Node::Delta(x)), Node::Delta(y)) if x.checked_add(y).is_some() => *x += y,
+            (Some(Node::Move(x)), Node::Move(y)) if x.checked_add(y).is_some() => *x += y,
+            (Some(Node::Comment(x)), Node::Comment(y)) => x.push_str(&y),
+            (_, node) => a.push(node),
+        }
+        elide_net_zero(&mut a);
+    }
+    return a;
+}
+
+/// Diagnostic describes a single problem found in the source, such as an
+/// unmatched bracket, along with the byte range it applies to.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub range: std::ops::Range<usize>,
+    pub message: String,
+}
+
+/// diagnostics validates the bracket structure of the source without stopping
+/// at the first problem, so that every unmatched '[' and ']' is reported.
+/// Diagnostics are returned in source order.
+pub fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    let mut opens: Vec<usize> = vec![];
+
+    for (offset, ch) in source.char_indices() {
+        match ch {
+            '[' => opens.push(offset),
+            ']' => match opens.pop() {
+                Some(_) => {}
+                None => diags.push(Diagnostic {
+                    range: offset..offset + 1,
+                    message: "closing jump-if-nonzero ']' has no matching opening jump-if-zero '['".to_string(),
+                }),
+            },
+            _ => {}
+        }
+    }
+
+    for offset in opens {
+        diags.push(Diagnostic {
+            range: offset..offset + 1,
+            message: "opening jump-if-zero '[' is never closed with a jump-if-nonzero ']'".to_string(),
+        });
+    }
+
+    diags.sort_by_key(|d| d.range.start);
+    return diags;
+}
+
+/// line_col converts a byte offset in the source into a 1-based line and
+/// column pair.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    return (line, col);
+}
+
+/// explain_parse logs the coalescing decision parse made for a single token,
+/// given the length of the current span before the token was handled and the
+/// span the token ended up in.
+fn explain_parse(kind: &TokenKind, offset: usize, len: usize, span: &[Node]) {
+    let decision = match (kind, span.last()) {
+        (TokenKind::JumpRight, _) => "opened a new Block".to_string(),
+        (TokenKind::JumpLeft, _) => match span.last() {
+            Some(last) => format!("closed {}", last.label()),
+            None => return,
+        },
+        (_, _) if span.len() < len => format!("'{}' cancelled out the previous node, which was dropped", kind.symbol()),
+        (_, None) => return,
+        (_, Some(last)) if span.len() == len => format!("merged '{}' into {}", kind.symbol(), last.label()),
+        (TokenKind::Input | TokenKind::Output | TokenKind::Flush, Some(last)) => {
+            format!("started new {} node, which is never merged", last.label())
+        }
+        (_, Some(last)) => match len.checked_sub(1).map(|i| &span[i]) {
+            Some(prev) => format!(
+                "started new {} node because previous was {}",
+                last.label(),
+                prev.label()
+            ),
+            None => format!("started new {} node because it is first in its block", last.label()),
+        },
+    };
+    eprintln!("parse: offset {}: {}", offset, decision);
+}
+
+/// rewrite rebuilds a tree of nodes from the innermost blocks outwards, using
+/// an explicit stack so that deep nesting can't overflow the call stack. Every
+/// list of nodes, once its blocks have been rewritten, is passed to fold, and
+/// every Block, once its body has been folded, is passed to lower.
+fn rewrite(nodes: Vec<Node>, lower: impl Fn(Node) -> Node, fold: impl Fn(Vec<Node>) -> Vec<Node>) -> Vec<Node> {
+    // each level holds the offset of its Block, the nodes left to rewrite
+    // and the nodes already rewritten
+    let mut levels: Vec<(usize, std::vec::IntoIter<Node>, Vec<Node>)> = vec![(0, nodes.into_iter(), vec![])];
+    loop {
+        let Some((_, rest, done)) = levels.last_mut() else {
+            return vec![];
+        };
+        match rest.next() {
+            Some(Node::Block(offset, body)) => levels.push((offset, body.into_iter(), vec![])),
+            Some(node) => done.push(node),
+            None => {
+                let Some((offset, _, done)) = levels.pop() else {
+                    return vec![];
+                };
+                let done = fold(done);
+                match levels.last_mut() {
+                    Some((_, _, parent)) => parent.push(lower(Node::Block(offset, done))),
+                    None => return done,
+                }
+            }
+        }
+    }
+}
+
+/// Pass is one optimization pass over a whole program, with the name that
+/// reports and option lists know it by.
+pub struct Pass {
+    pub name: &'static str,
+    pub run: Box<dyn Fn(Vec<Node>) -> Vec<Node>>,
+}
+
+/// passes returns the built-in optimization passes in the order they are meant
+/// to run in. Callers may reorder, drop or add to them before handing them to
+/// run_passes.
+pub fn passes() -> Vec<Pass> {
+    return vec![
+        Pass {
+            name: "coalesce",
+            run: Box::new(coalesce),
+        },
+        Pass {
+            name: "scan-loops",
+            run: Box::new(lower_scan_loops),
+        },
+        Pass {
+            name: "clear-loops",
+            run: Box::new(lower_clear_loops),
+        },
+        Pass {
+            name: "clear-ranges",
+            run: Box::new(lower_clear_ranges),
+        },
+        Pass {
+            name: "mul-loops",
+            run: Box::new(lower_mul_loops),
+        },
+        Pass {
+            name: "offset-deltas",
+            run: Box::new(lower_offset_deltas),
+        },
+    ];
+}
+
+/// run_passes runs each pass over the program in turn.
+pub fn run_passes(nodes: Vec<Node>, passes: &[Pass]) -> Vec<Node> {
+    return passes.iter().fold(nodes, |nodes, pass| (pass.run)(nodes));
+}
+
+/// coalesce merges adjacent deltas, moves and comments at every level, and
+/// drops the ones that cancel out, as parse does. Parsed programs are already
+/// coalesced, but programs built or rewritten by hand may not be.
+pub fn coalesce(nodes: Vec<Node>) -> Vec<Node> {
+    return rewrite(nodes, |node| node, |nodes| concat_programs(vec![], nodes));
+}
+
+/// lower_clear_loops replaces every Block whose body is a single increment or
+/// decrement with a SetZero.
+pub fn lower_clear_loops(nodes: Vec<Node>) -> Vec<Node> {
+    let lower = |node| match node {
+        Node::Block(_, body) if matches!(body[..], [Node::Delta(-1 | 1)]) => Node::SetZero,
+        node => node,
+    };
+    return rewrite(nodes, lower, |nodes| nodes);
+}
+
+/// lower_clear_ranges replaces every run of SetZeros and moves that clears
+/// two or more neighbouring cells, and each cell between them, with a
+/// ClearRange followed by the net move. It builds on lower_clear_loops.
+pub fn lower_clear_ranges(nodes: Vec<Node>) -> Vec<Node> {
+    let fold = |nodes: Vec<Node>| {
+        let mut lowered = vec![];
+        let mut run = vec![];
+        for node in nodes {
+            match node {
+                Node::SetZero | Node::Move(_) => run.push(node),
+                node => {
+                    lowered.extend(clear_range(std::mem::take(&mut run)));
+                    lowered.push(node);
+                }
+            }
+        }
+        lowered.extend(clear_range(run));
+        return lowered;
+    };
+    return rewrite(nodes, |node| node, fold);
+}
+
+/// clear_range folds a run of SetZeros and moves for lower_clear_ranges,
+/// leaving it as it is unless the cleared cells are contiguous.
+fn clear_range(run: Vec<Node>) -> Vec<Node> {
+    let mut cleared = std::collections::BTreeSet::new();
+    let mut pos = 0i32;
+    for node in &run {
+        match node {
+            Node::Move(m) => pos += i32::from(*m),
+            _ => _ = cleared.insert(pos),
+        }
+    }
+    let (Some(&from), Some(&to)) = (cleared.first(), cleared.last()) else {
+        return run;
+    };
+    let (Ok(from), Ok(to), Ok(net)) = (i16::try_from(from), i16::try_from(to), i16::try_from(pos)) else {
+        return run;
+    };
+    if cleared.len() < 2 || cleared.len() != (to - from) as usize + 1 {
+        return run;
+    }
+    return match net {
+        0 => vec![Node::ClearRange(from, to)],
+        net => vec![Node::ClearRange(from, to), Node::Move(net)],
+    };
+}
+
+/// lower_scan_loops replaces every Block whose body is a single move with a
+/// Scan.
+pub fn lower_scan_loops(nodes: Vec<Node>) -> Vec<Node> {
+    let lower = |node| match node {
+        Node::Block(offset, body) => match body[..] {
+            [Node::Move(step)] => Node::Scan(step),
+            _ => Node::Block(offset, body),
+        },
+        node => node,
+    };
+    return rewrite(nodes, lower, |nodes| nodes);
+}
+
+/// lower_offset_deltas replaces every run of two or more deltas and moves,
+/// along with any comments between them, with OffsetDeltas, or with a single
+/// Move if the run changes no cells.
+pub fn lower_offset_deltas(nodes: Vec<Node>) -> Vec<Node> {
+    let fold = |nodes: Vec<Node>| {
+        let mut lowered = vec![];
+        let mut run = vec![];
+        for node in nodes {
+            match node {
+                Node::Delta(_) | Node::Move(_) | Node::Comment(_) => run.push(node),
+                node => {
+                    lowered.extend(offset_deltas(std::mem::take(&mut run)));
+                    lowered.push(node);
+                }
+            }
+        }
+        lowered.extend(offset_deltas(run));
+        return lowered;
+    };
+    return rewrite(nodes, |node| node, fold);
+}
+
+/// offset_deltas folds a run of deltas, moves and comments for
+/// lower_offset_deltas, leaving it as it is if there is nothing to fold.
+fn offset_deltas(run: Vec<Node>) -> Vec<Node> {
+    let mut deltas: std::collections::BTreeMap<i32, i32> = std::collections::BTreeMap::new();
+    let (mut pos, mut steps) = (0i32, 0);
+    for node in &run {
+        match node {
+            Node::Delta(d) => *deltas.entry(pos).or_default() += d,
+            Node::Move(m) => pos += i32::from(*m),
+            _ => continue,
+        }
+        steps += 1;
+    }
+    let terms: Option<Vec<(i16, i32)>> = deltas
+        .into_iter()
+        .filter(|&(_, delta)| delta != 0)
+        .map(|(offset, delta)| Some((i16::try_from(offset).ok()?, delta)))
+        .collect();
+    let (Some(terms), Ok(net), true) = (terms, i16::try_from(pos), steps >= 2) else {
+        return run;
+    };
+
+    return match (terms.is_empty(), net) {
+        (true, 0) => vec![],
+        (true, net) => vec![Node::Move(net)],
+        (false, net) => vec![Node::OffsetDeltas(terms, net)],
+    };
+}
+
+/// lower_mul_loops replaces every Block that only changes and moves between
+/// cells, returns to where it started, and decrements its own cell by one per
+/// iteration with a MulAdd.
+pub fn lower_mul_loops(nodes: Vec<Node>) -> Vec<Node> {
+    let lower = |node| match node {
+        Node::Block(offset, body) => match mul_terms(&body) {
+            Some(terms) => Node::MulAdd(terms),
+            None => Node::Block(offset, body),
+        },
+        node => node,
+    };
+    return rewrite(nodes, lower, |nodes| nodes);
+}
+
+/// mul_terms returns the cells, relative to the loop's own cell, that a
+/// multiply loop body adds to and by how much per iteration, or None if the
+/// body isn't a multiply loop.
+fn mul_terms(body: &[Node]) -> Option<Vec<(i16, i32)>> {
+    let mut deltas: std::collections::BTreeMap<i32, i32> = std::collections::BTreeMap::new();
+    let mut pos = 0i32;
+    for node in body {
+        match node {
+            Node::Comment(_) => {}
+            Node::Delta(d) => *deltas.entry(pos).or_default() += d,
+            Node::Move(m) => pos += i32::from(*m),
+            _ => return None,
+        }
+    }
+    if pos != 0 || deltas.remove(&0) != Some(-1) {
+        return None;
+    }
+
+    return deltas
+        .into_iter()
+        .filter(|&(_, factor)| factor != 0)
+        .map(|(offset, factor)| Some((i16::try_from(offset).ok()?, factor)))
+        .collect();
+}
+
+/// FAN_IN_THRESHOLD is how many distinct cells a loop may touch before the
+/// summary reports it as a high fan-in loop.
+pub const FAN_IN_THRESHOLD: usize = 5;
+
+/// Summary holds static metrics about a parsed program, gathered without
+/// running it.
+#[derive(Debug, Default)]
+pub struct Summary {
+    instructions: usize,
+    loops: usize,
+    max_depth: usize,
+    kinds: std::collections::BTreeMap<&'static str, usize>,
+    // extent is the range of cells relative to the starting cell that the
+    // program may touch, or None if a loop moves the pointer by a nonzero net
+    // amount per iteration, in which case the extent can't be known statically.
+    extent: Option<(i32, i32)>,
+    // fan_in lists the source offset of every loop that touches more than
+    // FAN_IN_THRESHOLD distinct cells per iteration, with how many it touches.
+    // Such loops, typically dispatching on cell values, are poor candidates
+    // for the linear loop optimizations.
+    fan_in: Vec<(usize, usize)>,
+}
+
+impl Summary {
+    pub fn new(instructions: usize, nodes: &[Node]) -> Summary {
+        let mut summary = Summary {
+            instructions,
+            ..Default::default()
+        };
+        summary.count(nodes, 1);
+
+        let mut extent = (0, 0);
+        summary.extent = Summary::walk(nodes, 0, &mut extent).map(|_| extent);
+        return summary;
+    }
+
+    /// count tallies nodes by kind, along with loops and their nesting depth.
+    fn count(&mut self, nodes: &[Node], depth: usize) {
+        for node in nodes {
+            *self.kinds.entry(node.kind()).or_default() += 1;
+            if let Node::Block(offset, body) = node {
+                self.loops += 1;
+                self.max_depth = self.max_depth.max(depth);
+                let mut cells = std::collections::BTreeSet::from([0]);
+                if Summary::touch(body, 0, &mut cells) == Some(0) && cells.len() > FAN_IN_THRESHOLD {
+                    self.fan_in.push((*offset, cells.len()));
+                }
+                self.count(body, depth + 1);
+            }
+        }
+    }
+
+    /// walk follows the pointer through nodes starting at pos, widening extent
+    /// to cover every cell visited. It returns the final pointer position, or
+    /// None when the position depends on how many times a loop runs.
+    fn walk(nodes: &[Node], mut pos: i32, extent: &mut (i32, i32)) -> Option<i32> {
+        for node in nodes {
+            match node {
+                Node::Move(i) => {
+                    pos += *i as i32;
+                    *extent = (extent.0.min(pos), extent.1.max(pos));
+                }
+                Node::Block(_, body) => {
+                    let end = Summary::walk(body, pos, extent)?;
+                    if end != pos {
+                        return None;
+                    }
+                }
+                Node::MulAdd(terms) => {
+                    for (offset, _) in terms {
+                        let cell = pos + *offset as i32;
+                        *extent = (extent.0.min(cell), extent.1.max(cell));
+                    }
+                }
+                Node::Scan(_) => return None,
+                Node::ClearRange(from, to) => {
+                    *extent = (extent.0.min(pos + *from as i32), extent.1.max(pos + *to as i32));
+                }
+                Node::OffsetDeltas(terms, net) => {
+                    for (offset, _) in terms {
+                        let cell = pos + *offset as i32;
+                        *extent = (extent.0.min(cell), extent.1.max(cell));
+                    }
+                    pos += *net as i32;
+                    *extent = (extent.0.min(pos), extent.1.max(pos));
+                }
+                _ => {}
+            }
+        }
+        return Some(pos);
+    }
+
+    /// touch follows the pointer through nodes starting at pos like walk does,
+    /// collecting every cell that is read or written. It returns the final
+    /// pointer position, or None when it depends on how many times a loop runs.
+    fn touch(nodes: &[Node], mut pos: i32, cells: &mut std::collections::BTreeSet<i32>) -> Option<i32> {
+        for node in nodes {
+            match node {
+                Node::Comment(_) | Node::Flush => {}
+                Node::Move(i) => pos += *i as i32,
+                Node::Delta(_) | Node::Read | Node::Write | Node::SetZero => {
+                    cells.insert(pos);
+                }
+                Node::MulAdd(terms) => {
+                    cells.insert(pos);
+                    cells.extend(terms.iter().map(|(offset, _)| pos + *offset as i32));
+                }
+                Node::Block(_, body) => {
+                    cells.insert(pos);
+                    if Summary::touch(body, pos, cells)? != pos {
+                        return None;
+                    }
+                }
+                Node::Scan(_) => return None,
+                Node::ClearRange(from, to) => cells.extend(pos + *from as i32..=pos + *to as i32),
+                Node::OffsetDeltas(terms, net) => {
+                    cells.extend(terms.iter().map(|(offset, _)| pos + *offset as i32));
+                    pos += *net as i32;
+                }
+            }
+        }
+        return Some(pos);
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Summary:")?;
+        writeln!(f, "  instructions: {}", self.instructions)?;
+        writeln!(f, "  nodes: {}", self.kinds.values().sum::<usize>())?;
+        writeln!(f, "  loops: {}", self.loops)?;
+        writeln!(f, "  max depth: {}", self.max_depth)?;
+        match self.extent {
+            Some((lo, hi)) => writeln!(f, "  memory extent: {}..={}", lo, hi)?,
+            None => writeln!(f, "  memory extent: unbounded")?,
+        }
+        writeln!(f, "  high fan-in loops: {}", self.fan_in.len())?;
+        for (offset, cells) in &self.fan_in {
+            writeln!(f, "    offset {}: {} cells", offset, cells)?;
+        }
+        writeln!(f, "  nodes by kind:")?;
+        for (kind, n) in &self.kinds {
+            writeln!(f, "    {}: {}", kind, n)?;
+        }
+        return Ok(());
+    }
+}
+
+/// parse_rle parses run-length encoded brainfuck straight into nodes, without
+/// lexing each character into a token first. Every instruction may be followed
+/// by a decimal repeat count: runs of '+', '-', '>' and '<' become a single
+/// Delta or Move of that magnitude, while ',' and '.' are repeated. Brackets
+/// can't be repeated. Adjacent runs are coalesced the same way parse does.
+/// Returns the number of instructions (ignoring repeat counts) and the nodes.
+pub fn parse_rle(content: &str) -> Result<(usize, Vec<Node>)> {
+    let mut spans: Vec<Vec<Node>> = vec![vec![]];
+    let mut opens: Vec<usize> = vec![];
+    let mut instructions = 0;
+
+    let mut chars = content.char_indices().peekable();
+    while let Some((offset, ch)) = chars.next() {
+        let mut digits = String::new();
+        while let Some((_, d)) = chars.next_if(|(_, d)| d.is_ascii_digit()) {
+            digits.push(d);
+        }
+        let count: u32 = match digits.is_empty() {
+            true => 1,
+            false => digits.parse().ok().context(InvalidRunLengthSnafu {
+                offset,
+                reason: format!("repeat count '{}' is too large", digits),
+            })?,
+        };
+        if "+-<>,.[]".contains(ch) {
+            instructions += 1;
+        }
+
+        let span = spans.last_mut().context(InvariantViolationSnafu {
+            reason: "expecting 'spans' stack to not be empty",
+        })?;
+        match ch {
+            '+' | '-' => {
+                let delta = i32::try_from(count).ok().context(InvalidRunLengthSnafu {
+                    offset,
+                    reason: format!("cannot change a cell by {}", count),
+                })?;
+                let delta = if ch == '+' { delta } else { -delta };
+                match span.last_mut() {
+                    Some(Node::Delta(a)) if a.checked_add(delta).is_some() => *a += delta,
+                    _ => span.push(Node::Delta(delta)),
+                }
+                elide_net_zero(span);
+            }
+            '>' | '<' => {
+                let mv = i16::try_from(count).ok().context(InvalidRunLengthSnafu {
+                    offset,
+                    reason: format!("cannot move by {} cells", count),
+                })?;
+                let mv = if ch == '>' { mv } else { -mv };
+                match span.last_mut() {
+                    Some(Node::Move(a)) if a.checked_add(mv).is_some() => *a += mv,
+                    _ => span.push(Node::Move(mv)),
+                }
+                elide_net_zero(span);
+            }
+            ',' => span.extend((0..count).map(|_| Node::Read)),
+            '.' => span.extend((0..count).map(|_| Node::Write)),
+            '[' | ']' if !digits.is_empty() => {
+                return InvalidRunLengthSnafu {
+                    offset,
+                    reason: format!("'{}' cannot be repeated", ch),
+                }
+                .fail();
+            }
+            '[' => {
+                opens.push(offset);
+                spans.push(vec![]);
+            }
+            ']' => {
+                let Some(open) = opens.pop() else {
+                    let (line, col) = line_col(content, offset);
+                    return UnexpectedCloseSnafu { line, col }.fail();
+                };
+                let prev = spans.pop().context(InvariantViolationSnafu {
+                    reason: "expecting 'spans' stack to hold a span for every unclosed block",
+                })?;
+                let span = spans.last_mut().context(InvariantViolationSnafu {
+                    reason: "expecting 'spans' stack to hold the parent of a closed block",
+                })?;
+                span.push(Node::Block(open, prev));
+            }
+            c => {
+                let comment = format!("{}{}", c, digits);
+                match span.last_mut() {
+                    Some(Node::Comment(a)) => a.push_str(&comment),
+                    _ => span.push(Node::Comment(comment)),
+                }
+            }
+        }
+    }
+
+    if let Some(&open) = opens.last() {
+        let (line, col) = line_col(content, open);
+        return UnclosedJumpSnafu { line, col }.fail();
+    }
+    let nodes = spans.pop().context(InvariantViolationSnafu {
+        reason: "expecting 'spans' stack to not be empty at end of parsing",
+    })?;
+    return Ok((instructions, nodes));
+}
+
+#[derive(Debug, Clone)]
+pub struct State<'io> {
+    pub counter: usize,
+    pub pointer: i16,
+    // data holds every allocated cell of the tape from the leftmost to the
+    // rightmost, with cell 0 at index origin. Its width is the cell width.
+    pub data: Tape,
+    pub origin: usize,
+    pub profile: Option<Profile>,
+    pub max_tape_bytes: Option<usize>,
+    // tape_size caps the tape at this many cells, numbered from zero, and
+    // no_negative forbids the cells left of zero.
+    pub tape_size: Option<usize>,
+    pub no_negative: bool,
+    // wrap_tape makes the tape a ring of this many cells, which wraps the
+    // pointer around at either end. The tape must already hold that many.
+    pub wrap_tape: Option<usize>,
+    // sample_every calls on_sample with the state every this many
+    // instructions. Both are set with sample.
+    pub sample_every: Option<usize>,
+    pub on_sample: Option<Sampler<'io>>,
+    pub until_tape: Option<Vec<u8>>,
+    pub halted: bool,
+    pub trace: Option<TraceFormat>,
+    pub muted: bool,
+    pub loop_polarity: LoopPolarity,
+    pub overflow: Overflow,
+    pub eof: Eof,
+    pub input: Input<'io>,
+    pub newlines: Option<Newlines>,
+    pub frame: Option<Vec<u8>>,
+    // growth_events counts how many times either side of the tape had to be
+    // reallocated to grow.
+    pub growth_events: usize,
+    // loops_entered counts how many times a Block was reached, and back_edges
+    // how many times one jumped back to run its body again.
+    pub loops_entered: usize,
+    pub back_edges: usize,
+    pub max_loop_iterations: Option<u64>,
+    // warn_below warns once when the pointer first moves below it.
+    pub warn_below: Option<i16>,
+    // max_steps stops the program once it has run this many steps.
+    pub max_steps: Option<usize>,
+    // deadline halts the program once it is reached.
+    pub deadline: Option<std::time::Instant>,
+    // stopped_by is the limit that halted the program, if one did.
+    pub stopped_by: Option<Limit>,
+    // fixed_tape means the tape was allocated up front and never grows; the
+    // pointer must stay within data.
+    pub fixed_tape: bool,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    // watched holds the cells traced with --trace-cells, along with the value
+    // each last had.
+    pub watched: Vec<(i16, Cell)>,
+    // record collects every byte the program writes, even while muted, when
+    // output needs to be compared afterwards.
+    pub record: Option<Vec<u8>>,
+    pub snapshots: Option<Snapshots>,
+    // output_record_size pads every Write to this many bytes.
+    pub output_record_size: Option<usize>,
+    // hot counts how many times each node ran, keyed by the node's address,
+    // for --emit=interpreter-trace-summary.
+    pub hot: Option<HashMap<usize, usize>>,
+    // locations maps the address of each node to the line and column of the
+    // source it came from, so that errors can say where they happened, and
+    // current is the address of the node last stepped into.
+    pub locations: Option<HashMap<usize, (usize, usize)>>,
+    pub current: usize,
+    pub executions: Executions,
+    pub output: Output<'io>,
+    // on_output passes every byte the program writes through a filter, set
+    // with filter_output, which may replace the byte or drop it.
+    pub on_output: Option<Shared<dyn FnMut(u8) -> Option<u8> + 'io>>,
+    // pre_eval rewrites the nodes handed to run before any of them run, set
+    // with rewrite_nodes.
+    pub pre_eval: Option<Rewrite<'io>>,
+}
+
+/// Output buffers what the program writes to stdout, so that stdout isn't
+/// written to for every byte. Whatever is still buffered is written out when
+/// it is dropped, so output isn't lost when a program stops with an error.
+#[derive(Debug, Default)]
+pub struct Output<'io> {
+    buf: Vec<u8>,
+    // sink is where the buffer is written instead of stdout, if set.
+    sink: Option<Shared<dyn Write + 'io>>,
+}
+
+impl<'io> Output<'io> {
+    /// to sends output to the given writer instead of stdout. Passing a
+    /// borrowed writer, such as a `&mut Vec<u8>`, lets the caller look at what
+    /// was written once the state is done with.
+    pub fn to(sink: impl Write + 'io) -> Output<'io> {
+        return Output {
+            buf: vec![],
+            sink: Some(Shared(Rc::new(RefCell::new(sink)))),
+        };
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= OUTPUT_BUFFER_SIZE {
+            self.flush()?;
+        }
+        return Ok(());
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        match &self.sink {
+            Some(Shared(sink)) => {
+                let mut sink = sink.borrow_mut();
+                sink.write_all(&self.buf).context(WriteOutputSnafu)?;
+                sink.flush().context(WriteOutputSnafu)?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&self.buf).context(WriteOutputSnafu)?;
+                stdout.flush().context(WriteOutputSnafu)?;
+            }
+        }
+        self.buf.clear();
+        return Ok(());
+    }
+}
+
+/// A clone starts out empty, since the output buffered so far still belongs to
+/// the original, but writes to the same place.
+impl<'io> Clone for Output<'io> {
+    fn clone(&self) -> Output<'io> {
+        return Output {
+            buf: vec![],
+            sink: self.sink.clone(),
+        };
+    }
+}
+
+impl Drop for Output<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Shared holds a reader, writer or callback supplied by the caller, which a
+/// state and all of its clones use in turn.
+pub struct Shared<T: ?Sized>(Rc<RefCell<T>>);
+
+impl<T: ?Sized> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        return Shared(Rc::clone(&self.0));
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "Shared");
+    }
+}
+
+/// Sampler is a callback that is handed the state every few instructions.
+pub type Sampler<'io> = Shared<dyn FnMut(&State<'io>) + 'io>;
+
+/// Limit is one of the limits that can stop a program before it finishes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    // Steps is max_steps, holding the limit that was reached.
+    Steps(usize),
+    // LoopIterations is max_loop_iterations, holding the source offset of the
+    // loop that ran over and the limit.
+    LoopIterations(usize, u64),
+    // Deadline is the deadline passing.
+    Deadline,
+}
+
+/// Rewrite is a callback that rewrites a program before it runs.
+pub type Rewrite<'io> = Shared<dyn FnMut(Vec<Node>) -> Vec<Node> + 'io>;
+
+/// DEADLINE_EVERY is how many steps apart the clock is read to check whether
+/// a program ran past its deadline.
+pub const DEADLINE_EVERY: usize = 4096;
+
+/// SNAPSHOT_EVERY is how many instructions apart --tape-snapshots are taken
+/// by default.
+pub const SNAPSHOT_EVERY: usize = 1000;
+
+/// Snapshots tracks the tape snapshots written for --tape-snapshots.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    pub dir: String,
+    pub every: usize,
+    // taken is how many snapshots have been written, which numbers the next.
+    pub taken: usize,
+    // last is the counter at the last snapshot, so that nodes that don't
+    // count as instructions can't take the same snapshot twice.
+    pub last: usize,
+}
+
+/// Input is where the Read instruction takes its bytes from.
+#[derive(Debug, Clone, Default)]
+pub enum Input<'io> {
+    #[default]
+    Stdin,
+    // Buffer reads from bytes loaded up front, such as an input file.
+    Buffer {
+        bytes: Vec<u8>,
+        pos: usize,
+    },
+    // Repeat cycles through a fixed list of bytes forever, never reaching EOF.
+    Repeat {
+        bytes: Vec<u8>,
+        pos: usize,
+    },
+    // Reader reads from a reader supplied by the caller, a byte at a time.
+    Reader(Shared<dyn Read + 'io>),
+}
+
+impl<'io> Input<'io> {
+    /// reader reads input from the given reader instead of stdin, which may be
+    /// borrowed, such as a byte slice.
+    pub fn reader(reader: impl Read + 'io) -> Input<'io> {
+        return Input::Reader(Shared(Rc::new(RefCell::new(reader))));
+    }
+
+    /// buffered reads all of stdin or the reader up front, so that the same
+    /// input can be replayed by cloning the result. Other inputs are already
+    /// replayable.
+    pub fn buffered(self) -> Result<Input<'io>> {
+        let mut bytes = vec![];
+        match self {
+            Input::Stdin => {
+                std::io::stdin().read_to_end(&mut bytes).context(ReadInputSnafu)?;
+                return Ok(Input::Buffer { bytes, pos: 0 });
+            }
+            Input::Reader(Shared(reader)) => {
+                reader.borrow_mut().read_to_end(&mut bytes).context(ReadInputSnafu)?;
+                return Ok(Input::Buffer { bytes, pos: 0 });
+            }
+            input => return Ok(input),
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        match self {
+            Input::Stdin => {
+                let mut c = [0u8; 1];
+                std::io::stdin().read_exact(&mut c).context(ReadInputSnafu)?;
+                return Ok(c[0]);
+            }
+            Input::Buffer { bytes, pos } => {
+                // report running out the same way read_exact does for stdin
+                let c = bytes.get(*pos).copied();
+                *pos += 1;
+                return c
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+                    .context(ReadInputSnafu);
+            }
+            Input::Repeat { bytes, pos } => {
+                let c = bytes[*pos];
+                *pos = (*pos + 1) % bytes.len();
+                return Ok(c);
+            }
+            Input::Reader(Shared(reader)) => {
+                let mut c = [0u8; 1];
+                reader.borrow_mut().read_exact(&mut c).context(ReadInputSnafu)?;
+                return Ok(c[0]);
+            }
+        }
+    }
+}
+
+/// EXECUTED_KINDS names the kinds of node counted by Executions, in the order
+/// they are counted in.
+const EXECUTED_KINDS: [&str; 11] = [
+    "Delta",
+    "Move",
+    "Read",
+    "Write",
+    "Flush",
+    "Block",
+    "SetZero",
+    "MulAdd",
+    "Scan",
+    "OffsetDeltas",
+    "ClearRange",
+];
+
+/// Executions counts how many times each kind of node ran, where a Block
+/// counts once per loop entry, along with how many times loop bodies ran.
+#[derive(Debug, Clone, Default)]
+pub struct Executions {
+    kinds: [usize; EXECUTED_KINDS.len()],
+    iterations: usize,
+}
+
+impl Executions {
+    fn count(&mut self, node: &Node) {
+        let i = match node {
+            Node::Comment(_) => return,
+            Node::Delta(_) => 0,
+            Node::Move(_) => 1,
+            Node::Read => 2,
+            Node::Write => 3,
+            Node::Flush => 4,
+            Node::Block(..) => 5,
+            Node::SetZero => 6,
+            Node::MulAdd(_) => 7,
+            Node::Scan(_) => 8,
+            Node::OffsetDeltas(..) => 9,
+            Node::ClearRange(..) => 10,
+        };
+        self.kinds[i] += 1;
+    }
+
+    /// sorted lists the counts from most to least frequent, leaving out the
+    /// kinds that never ran.
+    pub fn sorted(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = EXECUTED_KINDS.iter().copied().zip(self.kinds).collect();
+        counts.push(("loop iterations", self.iterations));
+        counts.retain(|&(_, n)| n > 0);
+        counts.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+        return counts;
+    }
+}
+
+/// Profile records how many times each loop iterated, keyed by the loop's
+/// nesting path. A path is the list of source offsets of the opening '[' of
+/// every enclosing loop, outermost first.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    path: Vec<usize>,
+    iterations: HashMap<Vec<usize>, u64>,
+}
+
+impl Profile {
+    /// folded renders the profile as folded stacks rooted at `root`, sorted by
+    /// path so that the output is stable across runs.
+    pub fn folded(&self, root: &str) -> Vec<String> {
+        let mut paths: Vec<_> = self.iterations.iter().collect();
+        paths.sort();
+
+        return paths
+            .into_iter()
+            .map(|(path, count)| {
+                let frames: Vec<String> = path.iter().map(|offset| format!("loop@{}", offset)).collect();
+                format!("{};{} {}", root, frames.join(";"), count)
+            })
+            .collect();
+    }
+}
+
+/// States compare equal when their machines are in the same logical position:
+/// the same pointer over the same tape, once trailing zeros are trimmed from
+/// both sides. How they got there, such as the counter, input, output and any
+/// instrumentation, is ignored.
+impl PartialEq for State<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.pointer == other.pointer && self.tape() == other.tape();
+    }
+}
+
+impl Eq for State<'_> {}
+
+impl std::hash::Hash for State<'_> {
+    fn hash<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        self.pointer.hash(hasher);
+        self.tape().hash(hasher);
+    }
+}
+
+impl Default for State<'_> {
+    fn default() -> Self {
+        return State::new();
+    }
+}
+
+/// Arena keeps the tape of a finished run so that the next run can reuse it,
+/// rather than allocating a new tape every time. This helps when running many
+/// short programs one after another.
+#[derive(Debug, Default)]
+pub struct Arena {
+    data: Tape,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        return Arena::default();
+    }
+
+    /// state returns a fresh state whose tape is made from the arena's buffer,
+    /// at the width of the last tape it kept.
+    pub fn state<'io>(&mut self) -> State<'io> {
+        let mut data = std::mem::take(&mut self.data);
+        data.resize(0);
+        data.resize(1);
+        return State::with_tape(data);
+    }
+
+    /// reclaim takes back the tape of a state once it is done with, keeping
+    /// whichever buffer has the most room.
+    pub fn reclaim(&mut self, state: State) {
+        if state.data.capacity() > self.data.capacity() {
+            self.data = state.data;
+        }
+    }
+}
+
+impl<'io> State<'io> {
+    pub fn new() -> State<'io> {
+        return State::with_tape(Tape::new(CellBits::Eight, 1));
+    }
+
+    /// with_tape builds a fresh state around the given tape buffer, which
+    /// holds at least the starting cell.
+    fn with_tape(data: Tape) -> State<'io> {
+        return State {
+            counter: 0,
+            pointer: 0,
+            data,
+            origin: 0,
+            profile: None,
+            max_tape_bytes: None,
+            tape_size: None,
+            no_negative: false,
+            wrap_tape: None,
+            sample_every: None,
+            on_sample: None,
+            until_tape: None,
+            halted: false,
+            trace: None,
+            muted: false,
+            loop_polarity: LoopPolarity::NonZero,
+            overflow: Overflow::Wrap,
+            eof: Eof::Unchanged,
+            input: Input::Stdin,
+            newlines: None,
+            frame: None,
+            growth_events: 0,
+            loops_entered: 0,
+            back_edges: 0,
+            max_loop_iterations: None,
+            warn_below: None,
+            max_steps: None,
+            deadline: None,
+            stopped_by: None,
+            fixed_tape: false,
+            bytes_in: 0,
+            bytes_out: 0,
+            watched: vec![],
+            record: None,
+            snapshots: None,
+            output_record_size: None,
+            hot: None,
+            locations: None,
+            current: 0,
+            executions: Executions::default(),
+            output: Output::default(),
+            on_output: None,
+            pre_eval: None,
+        };
+    }
+
+    /// sample calls sampler with the state every `every` instructions, which is
+    /// much cheaper than tracing each one.
+    pub fn sample(&mut self, every: usize, sampler: impl FnMut(&State<'io>) + 'io) {
+        self.sample_every = Some(every.max(1));
+        self.on_sample = Some(Shared(Rc::new(RefCell::new(sampler))));
+    }
+
+    /// rewrite_nodes has run pass the program through hook before running it,
+    /// after any optimizing, so that callers can add passes of their own.
+    pub fn rewrite_nodes(&mut self, hook: impl FnMut(Vec<Node>) -> Vec<Node> + 'io) {
+        self.pre_eval = Some(Shared(Rc::new(RefCell::new(hook))));
+    }
+
+    /// filter_output passes every byte the program writes through filter
+    /// before it is written, which returns the byte to write in its place or
+    /// None to drop it.
+    pub fn filter_output(&mut self, filter: impl FnMut(u8) -> Option<u8> + 'io) {
+        self.on_output = Some(Shared(Rc::new(RefCell::new(filter))));
+    }
+
+    /// write_byte sends one byte of program output to the state's output,
+    /// unless output is muted or the filter drops it, translating newlines to
+    /// the configured line ending.
+    fn write_byte(&mut self, c: u8) -> Result<()> {
+        let c = match &self.on_output {
+            Some(filter) => match (filter.0.borrow_mut())(c) {
+                Some(c) => c,
+                None => return Ok(()),
+            },
+            None => c,
+        };
+        if let Some(record) = self.record.as_mut() {
+            record.push(c);
+        }
+        if self.muted {
+            return Ok(());
+        }
+        self.bytes_out += 1;
+
+        // bytes are written as they are, so that binary output isn't encoded
+        let bytes: &[u8] = match (c, self.newlines) {
+            (b'\n', Some(Newlines::Crlf)) => b"\r\n",
+            _ => std::slice::from_ref(&c),
+        };
+
+        let Some(frame) = self.frame.as_mut() else {
+            return self.output.write(bytes);
+        };
+        frame.extend_from_slice(bytes);
+        if c == b'\n' || frame.len() >= FRAME_SIZE {
+            self.flush_frame()?;
+        }
+        return Ok(());
+    }
+
+    /// flush_frame writes out any output buffered for the current frame, when
+    /// output is framed.
+    pub fn flush_frame(&mut self) -> Result<()> {
+        let Some(frame) = self.frame.as_mut().filter(|f| !f.is_empty()) else {
+            return Ok(());
+        };
+
+        self.output.write(&(frame.len() as u32).to_be_bytes())?;
+        self.output.write(frame)?;
+        frame.clear();
+        return Ok(());
+    }
+
+    /// index returns where the cell at the given pointer is in data, or None
+    /// if the tape doesn't reach that far.
+    fn index(&self, pointer: i16) -> Option<usize> {
+        let i = usize::try_from(pointer as isize + self.origin as isize).ok()?;
+        return (i < self.data.len()).then_some(i);
+    }
+
+    /// cell_bits returns the width of the cells of the tape.
+    pub fn cell_bits(&self) -> CellBits {
+        return self.data.bits();
+    }
+
+    /// set_cell stores the value in the cell under the pointer, growing the
+    /// tape first if the pointer is past either end of it.
+    fn set_cell(&mut self, value: Cell) {
+        if !self.fixed_tape {
+            self.grow_tape();
+        }
+        let i = (self.pointer as isize + self.origin as isize) as usize;
+        self.data.set(i, value);
+    }
+
+    /// grow_tape extends the tape on whichever end the pointer is past so that
+    /// it covers the cell under the pointer. Growing to the left shifts every
+    /// cell over and moves the origin along with them.
+    fn grow_tape(&mut self) {
+        let capacity = self.data.capacity();
+        let i = self.pointer as isize + self.origin as isize;
+        if i < 0 {
+            let n = (-i) as usize;
+            self.data.prepend(n);
+            self.origin += n;
+        } else if i as usize >= self.data.len() {
+            self.data.resize(i as usize + 1);
+        }
+
+        if self.data.capacity() != capacity {
+            self.growth_events += 1;
+        }
+    }
+
+    /// extents returns how many cells the tape holds to the left of cell 0,
+    /// and how many from cell 0 rightwards.
+    pub fn extents(&self) -> (usize, usize) {
+        return (self.origin, self.data.len() - self.origin);
+    }
+
+    /// cells iterates over every allocated cell of the tape, from the leftmost
+    /// to the rightmost.
+    pub fn cells(&self) -> impl Iterator<Item = Cell> + '_ {
+        return self.data.iter();
+    }
+
+    /// sides returns the cells left of cell 0, ending at cell -1, and the cells
+    /// from cell 0 rightwards.
+    pub fn sides(&self) -> (Vec<Cell>, Vec<Cell>) {
+        let mut left: Vec<Cell> = self.cells().collect();
+        let right = left.split_off(self.origin);
+        return (left, right);
+    }
+
+    /// tape returns both sides of the tape with the zeros at either end
+    /// trimmed, so that tapes that differ only in how far they were grown look
+    /// the same. The left side ends at cell -1.
+    pub fn tape(&self) -> (Vec<Cell>, Vec<Cell>) {
+        let (mut left, mut right) = self.sides();
+        let start = left.iter().position(|&c| c != 0).unwrap_or(left.len());
+        left.drain(..start);
+        right.truncate(right.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1));
+        return (left, right);
+    }
+
+    /// cell_at returns the value of the cell at the given pointer, treating
+    /// cells that haven't been allocated yet as zero.
+    pub fn cell_at(&self, pointer: i16) -> Cell {
+        return self.index(pointer).and_then(|i| self.data.get(i)).unwrap_or(0);
+    }
+
+    /// peek returns the value of any cell without moving the pointer. Cells
+    /// the tape hasn't reached yet read as zero.
+    pub fn peek(&self, cell: i16) -> Cell {
+        return self.cell_at(self.ring_cell(cell));
+    }
+
+    /// poke stores the value, wrapped to the cell width, in any cell without
+    /// moving the pointer, growing the tape to reach it. A cell outside the
+    /// tape's bounds is an error, just as moving there would be.
+    pub fn poke(&mut self, cell: i16, value: Cell) -> Result<()> {
+        let cell = self.ring_cell(cell);
+        let pointer = std::mem::replace(&mut self.pointer, cell);
+        let checked = match self.fixed_tape {
+            true => self.check_fixed_bounds(),
+            false => self.check_tape_size().and_then(|_| self.check_tape_bytes()),
+        };
+        if checked.is_ok() {
+            self.set_cell(value & self.cell_bits().max());
+        }
+        self.pointer = pointer;
+        return checked;
+    }
+
+    /// ring_cell returns where the given cell is on a wrap_tape ring, or the
+    /// cell itself on a straight tape.
+    fn ring_cell(&self, cell: i16) -> i16 {
+        return match self.wrap_tape {
+            Some(n) => i32::from(cell).rem_euclid(n as i32) as i16,
+            None => cell,
+        };
+    }
+
+    /// cell_written runs the checks that need to happen whenever the cell
+    /// under the pointer was modified.
+    fn cell_written(&mut self) {
+        self.check_until_tape();
+        self.check_watched();
+    }
+
+    /// check_watched logs a change to the cell under the pointer if it is one
+    /// of the watched cells.
+    fn check_watched(&mut self) {
+        let value = self.cell_at(self.pointer);
+        for (cell, last) in self.watched.iter_mut().filter(|(c, _)| *c == self.pointer) {
+            if *last != value {
+                eprintln!(
+                    "watch: cell {} changed {}->{} at instruction {}",
+                    cell, last, value, self.counter
+                );
+                *last = value;
+            }
+        }
+    }
+
+    /// check_until_tape halts the program once the start of the right tape
+    /// matches the target pattern. Cells past the end of the tape are zero.
+    fn check_until_tape(&mut self) {
+        if let Some(pattern) = &self.until_tape {
+            let matched = pattern
+                .iter()
+                .enumerate()
+                .all(|(i, &b)| self.cell_at(i as i16) == Cell::from(b));
+            self.halted = self.halted || matched;
+        }
+    }
+
+    /// check_limits enforces max_steps and the deadline. Every instruction run
+    /// counts as a step, and so does every jump back to the start of a loop, so
+    /// that a loop with an empty body still runs into the limits. Reading the
+    /// clock is slow, so the deadline is only checked every DEADLINE_EVERY
+    /// steps.
+    fn check_limits(&mut self) {
+        let steps = self.counter + self.back_edges;
+        if let Some(limit) = self.max_steps.filter(|&limit| steps > limit) {
+            self.stop(Limit::Steps(limit));
+        }
+        if let (Some(deadline), true) = (self.deadline, steps.is_multiple_of(DEADLINE_EVERY)) {
+            if std::time::Instant::now() >= deadline {
+                self.stop(Limit::Deadline);
+            }
+        }
+    }
+
+    /// stop halts the program because it ran into the given limit.
+    fn stop(&mut self, limit: Limit) {
+        self.stopped_by = Some(limit);
+        self.halted = true;
+    }
+
+    /// tick counts one executed instruction, handing the state to on_sample
+    /// every `sample_every` instructions when sampling is enabled. Ticks happen
+    /// as an instruction starts, so a sample shows the pointer before the
+    /// sampled instruction takes effect.
+    fn tick(&mut self) {
+        self.counter += 1;
+        if let (Some(n), Some(sampler)) = (self.sample_every, &self.on_sample) {
+            if self.counter.is_multiple_of(n) {
+                let sampler = sampler.clone();
+                (sampler.0.borrow_mut())(self);
+            }
+        }
+    }
+
+    /// hex_dump formats the tape as rows of 16 cells in hex, each row labelled
+    /// with the number of its first cell, and the cell under the pointer in
+    /// brackets.
+    pub fn hex_dump(&self) -> String {
+        let width = self.cell_bits().bytes() * 2;
+        let first = -(self.origin as i64);
+        let pointer = i64::from(self.pointer) - first;
+
+        let mut out = format!("pointer: {}\n", self.pointer);
+        let cells: Vec<Cell> = self.cells().collect();
+        for (row, chunk) in cells.chunks(16).enumerate() {
+            out.push_str(&format!("{:6}:", first + row as i64 * 16));
+            for (i, c) in chunk.iter().enumerate() {
+                match (row * 16 + i) as i64 == pointer {
+                    true => out.push_str(&format!("[{:0width$x}]", c, width = width)),
+                    false => out.push_str(&format!(" {:0width$x} ", c, width = width)),
+                }
+            }
+            out.push('\n');
+        }
+        return out;
+    }
+
+    /// check_snapshot writes a hex dump of the tape to the next numbered file
+    /// whenever another --snapshot-every instructions have run.
+    fn check_snapshot(&mut self) -> Result<()> {
+        let counter = self.counter;
+        let Some(snapshots) = self.snapshots.as_ref() else {
+            return Ok(());
+        };
+        if counter == snapshots.last || !counter.is_multiple_of(snapshots.every) {
+            return Ok(());
+        }
+
+        let filename = format!("{}/snapshot-{:06}.txt", snapshots.dir, snapshots.taken);
+        std::fs::write(&filename, self.hex_dump()).context(FileWriteSnafu { filename })?;
+        if let Some(snapshots) = self.snapshots.as_mut() {
+            snapshots.taken += 1;
+            snapshots.last = counter;
+        }
+        return Ok(());
+    }
+
+    /// scan moves the pointer by step until it lands on a zero cell. A step of
+    /// one cell searches the tape directly, and if there is no zero cell left
+    /// in that direction, stops on the first cell past the end of the tape.
+    fn scan(&mut self, step: i16) -> Result<()> {
+        // a scan that runs off the end of a ring carries on from the other
+        // end, so only a straight tape can be searched in one go
+        if let (1 | -1, Some(i), None) = (step, self.index(self.pointer), self.wrap_tape) {
+            let target = match step {
+                1 => self.data.next_zero(i).unwrap_or(self.data.len()) as isize,
+                _ => self.data.last_zero(i).map_or(-1, |j| j as isize),
+            };
+            if let Ok(pointer) = i16::try_from(target - self.origin as isize) {
+                self.pointer = pointer;
+                return self.moved();
+            }
+            // fail from the last addressable cell, the way stepping there would
+            self.pointer = if step > 0 { i16::MAX } else { i16::MIN };
+        }
+
+        while self.cell_at(self.pointer) != 0 {
+            self.move_to(i32::from(self.pointer) + i32::from(step))?;
+        }
+        return Ok(());
+    }
+
+    /// move_to moves the pointer to the given cell, or fails if the cell is
+    /// past the last addressable one.
+    fn move_to(&mut self, target: i32) -> Result<()> {
+        let Ok(pointer) = i16::try_from(target) else {
+            let by = target - i32::from(self.pointer);
+            let reason = format!("moving by {} cells passes the last addressable cell", by);
+            return self.out_of_bounds(reason).fail();
+        };
+        self.pointer = pointer;
+        return self.moved();
+    }
+
+    /// moved makes sure the tape has room for the cell under the pointer after
+    /// the pointer has changed.
+    fn moved(&mut self) -> Result<()> {
+        self.pointer = self.ring_cell(self.pointer);
+        if let Some(floor) = self.warn_below.filter(|&floor| self.pointer < floor) {
+            eprintln!(
+                "warning: pointer moved to {}, below {}, at instruction {}",
+                self.pointer, floor, self.counter
+            );
+            // only the first time is worth a warning
+            self.warn_below = None;
+        }
+        if self.fixed_tape {
+            // trusted programs skip growth entirely; checked builds still
+            // turn a bad move into an error rather than an index panic
+            if cfg!(debug_assertions) {
+                self.check_fixed_bounds()?;
+            }
+        } else {
+            self.check_tape_size()?;
+            self.check_tape_bytes()?;
+            self.grow_tape();
+        }
+        return Ok(());
+    }
+
+    /// check_tape_size ensures the pointer stays within the cells allowed by
+    /// tape_size and no_negative, if set.
+    fn check_tape_size(&self) -> Result<()> {
+        ensure!(
+            self.pointer >= 0 || !(self.no_negative || self.tape_size.is_some()),
+            self.out_of_bounds("left of cell 0 on a tape without negative cells".to_string())
+        );
+        if let Some(size) = self.tape_size {
+            ensure!(
+                (self.pointer as usize) < size,
+                self.out_of_bounds(format!("past the end of a tape of {} cells", size))
+            );
+        }
+        return Ok(());
+    }
+
+    /// check_fixed_bounds ensures the pointer is within a fixed-size tape.
+    fn check_fixed_bounds(&self) -> Result<()> {
+        ensure!(
+            self.index(self.pointer).is_some(),
+            self.out_of_bounds(format!("outside the fixed tape of {} cells", self.data.len()))
+        );
+        return Ok(());
+    }
+
+    /// out_of_bounds describes the pointer being outside the tape, along with
+    /// the cells the tape holds, or may hold when its size is capped, and
+    /// where in the source the pointer moved.
+    fn out_of_bounds(&self, reason: String) -> TapeOutOfBoundsSnafu<i16, i32, i32, String, Option<(usize, usize)>> {
+        let (left, right) = self.extents();
+        let low = match self.no_negative || self.tape_size.is_some() {
+            true => 0,
+            false => -(left as i32),
+        };
+        let high = self.tape_size.unwrap_or(right) as i32 - 1;
+        return TapeOutOfBoundsSnafu {
+            pointer: self.pointer,
+            low,
+            high,
+            reason,
+            location: self.locations.as_ref().and_then(|l| l.get(&self.current).copied()),
+        };
+    }
+
+    /// check_tape_bytes ensures that growing the tape to cover the cell at the
+    /// current pointer stays within the configured byte limit, if any.
+    fn check_tape_bytes(&self) -> Result<()> {
+        let Some(limit) = self.max_tape_bytes else {
+            return Ok(());
+        };
+
+        let (left, right) = self.extents();
+        let left = left.max((-i32::from(self.pointer)).max(0) as usize);
+        let right = right.max((self.pointer.max(-1) + 1) as usize);
+        let bytes = (left + right) * self.cell_bits().bytes();
+        ensure!(
+            bytes <= limit,
+            self.out_of_bounds(format!("needs {} bytes of tape, over the limit of {}", bytes, limit))
+        );
+        return Ok(());
+    }
+}
+
+impl Node {
+    /// kind is the name of the node's variant.
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            Node::Comment(_) => "Comment",
+            Node::Delta(_) => "Delta",
+            Node::Move(_) => "Move",
+            Node::Read => "Read",
+            Node::Write => "Write",
+            Node::Flush => "Flush",
+            Node::Block(..) => "Block",
+            Node::SetZero => "SetZero",
+            Node::MulAdd(_) => "MulAdd",
+            Node::Scan(_) => "Scan",
+            Node::OffsetDeltas(..) => "OffsetDeltas",
+            Node::ClearRange(..) => "ClearRange",
+        };
+    }
+
+    /// label is a short description of the node for traces, which leaves out
+    /// the body of blocks.
+    pub fn label(&self) -> String {
+        return match self {
+            Node::Comment(_) => "Comment".to_string(),
+            Node::Delta(i) => format!("Delta({})", i),
+            Node::Move(i) => format!("Move({})", i),
+            Node::Read => "Read".to_string(),
+            Node::Write => "Write".to_string(),
+            Node::Flush => "Flush".to_string(),
+            Node::Block(offset, _) => format!("Block@{}", offset),
+            Node::SetZero => "SetZero".to_string(),
+            Node::MulAdd(terms) => {
+                let terms: Vec<String> = terms.iter().map(|(o, f)| format!("{}:{}", o, f)).collect();
+                format!("MulAdd({})", terms.join(","))
+            }
+            Node::Scan(step) => format!("Scan({})", step),
+            Node::OffsetDeltas(terms, net) => {
+                let terms: Vec<String> = terms.iter().map(|(o, d)| format!("{}:{}", o, d)).collect();
+                format!("OffsetDeltas({};{})", terms.join(","), net)
+            }
+            Node::ClearRange(from, to) => format!("ClearRange({}..={})", from, to),
+        };
+    }
+}
+
+/// A node displays as its label followed by a newline, with the body of a
+/// Block below it as an indented tree.
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return self.fmt_depth(f, 0);
+    }
+}
+
+impl Node {
+    fn fmt_depth(&self, f: &mut std::fmt::Formatter, depth: usize) -> std::fmt::Result {
+        writeln!(f, "{:indent$}{}", "", self.label(), indent = depth * 2)?;
+        if let Node::Block(_, body) = self {
+            for node in body {
+                node.fmt_depth(f, depth + 1)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// run_program runs brainfuck source to completion, reading the program's input
+/// from `input` as it asks for it and writing its output to `output` as it
+/// goes. Once input runs out, reads set the cell to 0, so that a loop such as
+/// `,[.,]` stops. It returns the final state so that the tape can be inspected
+/// afterwards; it holds on to `input` and `output` until it is dropped.
+pub fn run_program<'io>(src: &str, input: impl Read + 'io, output: impl Write + 'io) -> Result<State<'io>> {
+    return run_state(State::new(), src, input, output);
+}
+
+/// run_str runs brainfuck source against the given input and returns what it
+/// wrote, for when only the output matters.
+///
+/// ```
+/// let hello = include_str!("../examples/hello_world.b");
+/// assert_eq!(bfstk::run_str(hello, b"").unwrap(), b"Hello World!\n");
+/// ```
+pub fn run_str(src: &str, input: &[u8]) -> Result<Vec<u8>> {
+    let mut output = vec![];
+    run_program(src, input, &mut output)?;
+    return Ok(output);
+}
+
+/// run_program_in is run_program with the tape taken from an arena. Hand the
+/// returned state back with Arena::reclaim to reuse its tape for the next run.
+pub fn run_program_in<'io>(
+    arena: &mut Arena,
+    src: &str,
+    input: impl Read + 'io,
+    output: impl Write + 'io,
+) -> Result<State<'io>> {
+    return run_state(arena.state(), src, input, output);
+}
+
+fn run_state<'io>(
+    mut state: State<'io>,
+    src: &str,
+    input: impl Read + 'io,
+    output: impl Write + 'io,
+) -> Result<State<'io>> {
+    let nodes = parse(lex(src.to_string())?, false)?;
+
+    state.input = Input::reader(input);
+    state.output = Output::to(output);
+    state.eof = Eof::Zero;
+    for node in &nodes {
+        state = eval(state, node)?;
+        if state.halted {
+            break;
+        }
+    }
+
+    state.output.flush()?;
+    return Ok(state);
+}
+
+/// RunOutcome is how a run ended: the program finished, a limit stopped it
+/// early, or it failed with an error. Finished and stopped runs come with the
+/// final state.
+#[derive(Debug)]
+pub enum RunOutcome<'io> {
+    Completed(State<'io>),
+    Limited(Limit, State<'io>),
+    Failed(BFE),
+}
+
+impl<'io> RunOutcome<'io> {
+    /// limit returns the limit that stopped the run, if one did.
+    pub fn limit(&self) -> Option<Limit> {
+        match self {
+            RunOutcome::Limited(limit, _) => return Some(*limit),
+            _ => return None,
+        }
+    }
+
+    /// into_state returns the final state of a run that didn't fail, or the
+    /// error of one that did.
+    pub fn into_state(self) -> Result<State<'io>> {
+        match self {
+            RunOutcome::Completed(state) | RunOutcome::Limited(_, state) => return Ok(state),
+            RunOutcome::Failed(e) => return Err(e),
+        }
+    }
+}
+
+/// run runs parsed nodes on the state until the program finishes or halts,
+/// flushing its output at the end, and says how it ended. The state's pre_eval
+/// hook, if set, rewrites the nodes first.
+pub fn run<'io>(mut state: State<'io>, nodes: &[Node]) -> RunOutcome<'io> {
+    let rewritten;
+    let nodes = match state.pre_eval.clone() {
+        Some(hook) => {
+            rewritten = (hook.0.borrow_mut())(nodes.to_vec());
+            &rewritten[..]
+        }
+        None => nodes,
+    };
+    for node in nodes {
+        if state.halted {
+            break;
+        }
+        match eval(state, node) {
+            Ok(next) => state = next,
+            Err(e) => return RunOutcome::Failed(e),
+        }
+    }
+    if let Err(e) = state.flush_frame().and_then(|_| state.output.flush()) {
+        return RunOutcome::Failed(e);
+    }
+    match state.stopped_by {
+        Some(limit) => return RunOutcome::Limited(limit, state),
+        None => return RunOutcome::Completed(state),
+    }
+}
+
+/// Frame tracks a Block whose body is being run: where the next node of the
+/// body is, and how many times the body has been entered.
+struct Frame<'a> {
+    offset: usize,
+    body: &'a [Node],
+    next: usize,
+    iterations: u64,
+}
+
+/// eval runs a node to completion. Blocks are walked with an explicit stack of
+/// frames rather than by recursion, so that deeply nested loops don't grow the
+/// native stack.
+pub fn eval<'io>(mut state: State<'io>, node: &Node) -> Result<State<'io>> {
+    let mut frames: Vec<Frame> = vec![];
+    state = step(state, node, &mut frames)?;
+    state.check_snapshot()?;
+
+    while !frames.is_empty() {
+        state = advance(state, &mut frames)?;
+    }
+
+    return Ok(state);
+}
+
+/// advance runs the next node in the innermost frame, or once its body is done,
+/// either starts the next iteration or leaves the loop.
+fn advance<'a, 'io>(mut state: State<'io>, frames: &mut Vec<Frame<'a>>) -> Result<State<'io>> {
+    let Some(frame) = frames.last_mut() else {
+        return Ok(state);
+    };
+
+    if frame.next < frame.body.len() && !state.halted {
+        let body = frame.body;
+        let node = &body[frame.next];
+        frame.next += 1;
+        state = step(state, node, frames)?;
+        state.check_snapshot()?;
+    } else if !iterate(&mut state, frame)? {
+        frames.pop();
+        if let Some(profile) = state.profile.as_mut() {
+            profile.path.pop();
+        }
+    }
+    return Ok(state);
+}
+
+/// Stream runs a program lazily, one output byte at a time: each call to next
+/// runs just enough of the program to produce the next byte, so input is only
+/// read once the program asks for it. Output goes to the iterator rather than
+/// to stdout. The stream ends when the program does, or after the first error.
+pub struct Stream<'a, 'io> {
+    state: Option<State<'io>>,
+    nodes: std::slice::Iter<'a, Node>,
+    frames: Vec<Frame<'a>>,
+    pending: std::collections::VecDeque<u8>,
+}
+
+impl<'a, 'io> Stream<'a, 'io> {
+    pub fn new(mut state: State<'io>, nodes: &'a [Node]) -> Stream<'a, 'io> {
+        state.muted = true;
+        state.record = Some(vec![]);
+        return Stream {
+            state: Some(state),
+            nodes: nodes.iter(),
+            frames: vec![],
+            pending: std::collections::VecDeque::new(),
+        };
+    }
+
+    /// resume runs a single node, returning None once the program is done.
+    fn resume(&mut self, mut state: State<'io>) -> Result<Option<State<'io>>> {
+        if !self.frames.is_empty() {
+            return Ok(Some(advance(state, &mut self.frames)?));
+        }
+        if state.halted {
+            return Ok(None);
+        }
+        let Some(node) = self.nodes.next() else {
+            return Ok(None);
+        };
+        state = step(state, node, &mut self.frames)?;
+        state.check_snapshot()?;
+        return Ok(Some(state));
+    }
+}
+
+impl Iterator for Stream<'_, '_> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Result<u8>> {
+        loop {
+            if let Some(byte) = self.pending.pop_front() {
+                return Some(Ok(byte));
+            }
+            let state = self.state.take()?;
+            match self.resume(state) {
+                Ok(Some(mut state)) => {
+                    if let Some(record) = state.record.as_mut() {
+                        self.pending.extend(record.drain(..));
+                    }
+                    self.state = Some(state);
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// step executes a single node, or enters a Block by pushing a frame for its
+/// body if the loop condition holds.
+fn step<'a, 'io>(mut state: State<'io>, node: &'a Node, frames: &mut Vec<Frame<'a>>) -> Result<State<'io>> {
+    if let (Some(hot), false) = (state.hot.as_mut(), matches!(node, Node::Comment(_))) {
+        *hot.entry(node as *const Node as usize).or_default() += 1;
+    }
+    state.executions.count(node);
+    state.current = node as *const Node as usize;
+
+    let Node::Block(offset, body) = node else {
+        let mut state = match state.trace {
+            None => exec(state, node)?,
+            Some(format) => eval_traced(state, node, format)?,
+        };
+        state.check_limits();
+        return Ok(state);
+    };
+
+    // blocks are logged on entry so that they precede the steps of their body
+    if let Some(format) = state.trace {
+        let before = state.cell_at(state.pointer);
+        trace_step(format, state.counter + 1, &node.label(), state.pointer, before, before);
+    }
+    state.tick();
+    state.loops_entered += 1;
+    if let Some(profile) = state.profile.as_mut() {
+        profile.path.push(*offset);
+    }
+
+    let mut frame = Frame {
+        offset: *offset,
+        body,
+        next: 0,
+        iterations: 0,
+    };
+    if iterate(&mut state, &mut frame)? {
+        frames.push(frame);
+    } else if let Some(profile) = state.profile.as_mut() {
+        profile.path.pop();
+    }
+    return Ok(state);
+}
+
+/// iterate tests the loop condition of a frame, and if it holds, starts the
+/// next iteration of its body. It returns whether the body runs again.
+fn iterate(state: &mut State, frame: &mut Frame) -> Result<bool> {
+    if state.halted || (state.cell_at(state.pointer) != 0) != (state.loop_polarity == LoopPolarity::NonZero) {
+        return Ok(false);
+    }
+
+    if frame.iterations > 0 {
+        state.back_edges += 1;
+    }
+    state.check_limits();
+    frame.iterations += 1;
+    state.executions.iterations += 1;
+    if let Some(limit) = state.max_loop_iterations.filter(|&limit| frame.iterations > limit) {
+        state.stop(Limit::LoopIterations(frame.offset, limit));
+    }
+    if state.halted {
+        return Ok(false);
+    }
+    if let Some(profile) = state.profile.as_mut() {
+        *profile.iterations.entry(profile.path.clone()).or_default() += 1;
+    }
+    frame.next = 0;
+    return Ok(true);
+}
+
+/// eval_traced executes a node, logging the step with the value of the cell
+/// under the pointer before and after it ran.
+fn eval_traced<'io>(state: State<'io>, node: &Node, format: TraceFormat) -> Result<State<'io>> {
+    let (pointer, before) = (state.pointer, state.cell_at(state.pointer));
+    if let Node::Comment(_) = node {
+        return exec(state, node);
+    }
+
+    let state = exec(state, node)?;
+    trace_step(
+        format,
+        state.counter,
+        &node.label(),
+        pointer,
+        before,
+        state.cell_at(pointer),
+    );
+    return Ok(state);
+}
+
+fn trace_step(format: TraceFormat, step: usize, label: &str, pointer: i16, before: Cell, after: Cell) {
+    match format {
+        TraceFormat::Text => eprintln!(
+            "trace: step={} node={} pointer={} cell={}->{}",
+            step, label, pointer, before, after
+        ),
+        TraceFormat::Json => eprintln!(
+            r#"{{"step":{},"node":"{}","pointer":{},"before":{},"after":{}}}"#,
+            step, label, pointer, before, after
+        ),
+    }
+}
+
+/// exec executes a single node other than a Block, which eval steps through
+/// itself.
+fn exec<'io>(mut state: State<'io>, node: &Node) -> Result<State<'io>> {
+    match *node {
+        Node::Comment(_) => {}
+
+        Node::Delta(i) => {
+            state.tick();
+            let value =
+                state
+                    .overflow
+                    .apply(state.pointer, state.cell_at(state.pointer), i, state.cell_bits().max())?;
+            state.set_cell(value);
+            state.cell_written();
+        }
+
+        Node::Move(i) => {
+            state.tick();
+            state.move_to(i32::from(state.pointer) + i32::from(i))?;
+        }
+
+        Node::Read => {
+            state.tick();
+
+            // an interactive program should show its prompt before it waits
+            if let Input::Stdin | Input::Reader(_) = state.input {
+                state.flush_frame()?;
+                state.output.flush()?;
+            }
+            match state.input.read_byte() {
+                Ok(c) => {
+                    state.set_cell(c.into());
+                    state.bytes_in += 1;
+                }
+                Err(BFE::ReadInput { source }) if source.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    match state.eof {
+                        Eof::Unchanged => {}
+                        Eof::Zero => state.set_cell(0),
+                        Eof::Max => state.set_cell(state.cell_bits().max()),
+                        Eof::Error => return Err(BFE::ReadInput { source }),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            state.cell_written();
+        }
+        Node::Write => {
+            state.tick();
+            let c = state.cell_at(state.pointer);
+            match state.output_record_size {
+                // wider cells write only their low byte
+                None => state.write_byte(c as u8)?,
+                Some(size) => {
+                    let bytes = c.to_le_bytes();
+                    for i in 0..size {
+                        state.write_byte(bytes.get(i).copied().unwrap_or(0))?;
+                    }
+                }
+            }
+        }
+
+        Node::Flush => {
+            state.tick();
+            state.flush_frame()?;
+            state.output.flush()?;
+        }
+
+        Node::SetZero => {
+            state.tick();
+            state.set_cell(0);
+            state.cell_written();
+        }
+
+        Node::Scan(step) => {
+            state.tick();
+            state.scan(step)?;
+        }
+
+        Node::OffsetDeltas(ref terms, net) => {
+            state.tick();
+            let origin = state.pointer;
+            for &(offset, delta) in terms {
+                state.move_to(i32::from(origin) + i32::from(offset))?;
+                let value = state.overflow.apply(
+                    state.pointer,
+                    state.cell_at(state.pointer),
+                    delta,
+                    state.cell_bits().max(),
+                )?;
+                state.set_cell(value);
+                state.cell_written();
+            }
+            state.move_to(i32::from(origin) + i32::from(net))?;
+        }
+
+        Node::ClearRange(from, to) => {
+            state.tick();
+            // moving to both ends grows the tape to cover every cell between
+            let origin = state.pointer;
+            state.move_to(i32::from(origin) + i32::from(from))?;
+            state.move_to(i32::from(origin) + i32::from(to))?;
+            let first = (i32::from(origin) + i32::from(from) + state.origin as i32) as usize;
+            let last = (i32::from(origin) + i32::from(to) + state.origin as i32) as usize;
+            state.data.clear(first..=last);
+            state.pointer = origin;
+        }
+
+        Node::MulAdd(ref terms) => {
+            state.tick();
+            let value = state.cell_at(state.pointer);
+            if value != 0 {
+                let (origin, modulus) = (state.pointer, i128::from(state.cell_bits().max()) + 1);
+                for &(offset, factor) in terms {
+                    state.move_to(i32::from(origin) + i32::from(offset))?;
+                    let sum = i128::from(state.cell_at(state.pointer)) + i128::from(value) * i128::from(factor);
+                    state.set_cell(sum.rem_euclid(modulus) as Cell);
+                    state.cell_written();
+                }
+                state.pointer = origin;
+                state.set_cell(0);
+                state.cell_written();
+            }
+        }
+
+        Node::Block(..) => {
+            return InvariantViolationSnafu {
+                reason: "expecting Blocks to be stepped through by eval rather than executed",
+            }
+            .fail();
+        }
+    }
+
+    return Ok(state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// nodes lexes and parses the source, panicking if it isn't valid.
+    fn nodes(src: &str) -> Vec<Node> {
+        return parse(lex(src.to_string()).unwrap(), false).unwrap();
+    }
+
+    /// dump formats nodes the way --dump-ast prints them.
+    fn dump(nodes: &[Node]) -> String {
+        return nodes.iter().map(|node| node.to_string()).collect();
+    }
+
+    /// run_nodes runs nodes on the given state, recording what the program
+    /// writes instead of sending it to stdout.
+    fn run_nodes<'io>(mut state: State<'io>, nodes: &[Node]) -> Result<State<'io>> {
+        state.muted = true;
+        state.record = Some(vec![]);
+        for node in nodes {
+            state = eval(state, node)?;
+            if state.halted {
+                break;
+            }
+        }
+        return Ok(state);
+    }
+
+    fn run_on<'io>(state: State<'io>, src: &str) -> Result<State<'io>> {
+        return run_nodes(state, &nodes(src));
+    }
+
+    fn run(src: &str) -> Result<State<'static>> {
+        return run_on(State::new(), src);
+    }
+
+    fn written(state: &State) -> Vec<u8> {
+        return state.record.clone().unwrap_or_default();
+    }
+
+    #[test]
+    fn delta_wraps_at_the_cell_width() {
+        assert_eq!(run(&"+".repeat(256)).unwrap().cell_at(0), 0);
+        assert_eq!(run("-").unwrap().cell_at(0), 255);
+    }
+
+    #[test]
+    fn wide_cells_count_past_255() {
+        let state = State {
+            data: Tape::new(CellBits::Sixteen, 1),
+            ..State::new()
+        };
+        assert_eq!(run_on(state, &"+".repeat(256)).unwrap().cell_at(0), 256);
+
+        let state = State {
+            data: Tape::new(CellBits::Sixteen, 1),
+            ..State::new()
+        };
+        let state = run_on(state, "-").unwrap();
+        assert_eq!(state.cell_at(0), 65535);
+        // the cells are stored two bytes apiece
+        assert!(matches!(state.data, Tape::Sixteen(_)));
+    }
+
+    #[test]
+    fn overflow_modes_differ_at_zero() {
+        let state = State {
+            overflow: Overflow::Saturate,
+            ..State::new()
+        };
+        assert_eq!(run_on(state, "-").unwrap().cell_at(0), 0);
+
+        let state = State {
+            overflow: Overflow::Trap,
+            ..State::new()
+        };
+        let err = run_on(state, "-").unwrap_err();
+        assert!(matches!(
+            err,
+            BFE::CellOverflow {
+                pointer: 0,
+                value: 0,
+                delta: -1
+            }
+        ));
+    }
+
+    #[test]
+    fn deeply_nested_loops_run() {
+        let src = format!("+{}-{}", "[".repeat(5000), "]".repeat(5000));
+        assert_eq!(run(&src).unwrap().cell_at(0), 0);
+    }
+
+    #[test]
+    fn optimizer_passes_handle_deep_nesting() {
+        let src = format!("+{}-{}", "[".repeat(5000), "]".repeat(5000));
+        let lowered = lower_offset_deltas(lower_mul_loops(lower_clear_loops(lower_scan_loops(nodes(&src)))));
+        assert_eq!(lowered[0].label(), "Delta(1)");
+
+        // the innermost loop is a clear loop, 4999 blocks down
+        let mut depth = 0;
+        let mut node = &lowered[1];
+        while let Node::Block(_, body) = node {
+            node = &body[0];
+            depth += 1;
+        }
+        assert_eq!(depth, 4999);
+        assert_eq!(node.label(), "SetZero");
+        assert_eq!(run_nodes(State::new(), &lowered).unwrap().cell_at(0), 0);
+    }
+
+    #[test]
+    fn cat_copies_input_until_eof() {
+        let mut out = vec![];
+        run_program(",[.,]", &b"abc"[..], &mut out).unwrap();
+        assert_eq!(out, b"abc");
+    }
+
+    #[test]
+    fn run_str_returns_what_was_written() {
+        assert_eq!(run_str(",[.,]", b"meow").unwrap(), b"meow");
+        assert_eq!(run_str("+++", b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn state_reads_and_writes_borrowed_buffers() {
+        let mut out = vec![];
+        let mut state = State {
+            input: Input::reader(&b"hi"[..]),
+            output: Output::to(&mut out),
+            ..State::new()
+        };
+        for node in &nodes(",+.,+.") {
+            state = eval(state, node).unwrap();
+        }
+        drop(state);
+        assert_eq!(out, b"ij");
+    }
+
+    #[test]
+    fn output_filter_replaces_and_drops_bytes() {
+        let mut out = vec![];
+        let mut state = State {
+            input: Input::reader(&b"hello, world!"[..]),
+            output: Output::to(&mut out),
+            eof: Eof::Zero,
+            ..State::new()
+        };
+        state.filter_output(|c| (c != b'!').then_some(c.to_ascii_uppercase()));
+        for node in &nodes(",[.,]") {
+            state = eval(state, node).unwrap();
+        }
+        drop(state);
+        assert_eq!(out, b"HELLO, WORLD");
+    }
+
+    #[test]
+    fn samples_fire_every_n_instructions() {
+        let mut samples = vec![];
+        let mut state = State::new();
+        state.sample(2, |state| samples.push((state.counter, state.pointer)));
+        drop(run_on(state, "+>+>+>").unwrap());
+        assert_eq!(samples, [(2, 0), (4, 1), (6, 2)]);
+    }
+
+    #[test]
+    fn stream_yields_output_bytes() {
+        let program = nodes("++++++++[>+++++++++<-]>.+.");
+        let output: Result<Vec<u8>> = Stream::new(State::new(), &program).collect();
+        assert_eq!(output.unwrap(), b"HI");
+    }
+
+    #[test]
+    fn arena_runs_many_programs_on_one_tape() {
+        let mut arena = Arena::new();
+        for i in 0..1000 {
+            let src = format!("{}.>+", "+".repeat(i % 10));
+            let mut out = vec![];
+            let state = run_program_in(&mut arena, &src, &b""[..], &mut out).unwrap();
+            assert_eq!(state.cell_at(1), 1);
+            arena.reclaim(state);
+            assert_eq!(out, [(i % 10) as u8]);
+        }
+        assert!(arena.data.capacity() <= 16);
+    }
+
+    #[test]
+    fn states_compare_by_pointer_and_trimmed_tape() {
+        let a = run("+>+<-").unwrap();
+        let b = run(">+<").unwrap();
+        let c = run("<.>>>.<<>+<").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(b, c);
+        assert_ne!(a, run(">+").unwrap());
+
+        let hash = |state: &State| {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            state.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&a), hash(&c));
+    }
+
+    #[test]
+    fn summary_counts_loops_and_depth() {
+        let summary = Summary::new(0, &nodes("+[-]>[>[-]]<[->+<]"));
+        assert_eq!(summary.loops, 4);
+        assert_eq!(summary.max_depth, 2);
+    }
+
+    #[test]
+    fn fan_in_tells_copy_loops_from_dispatch_loops() {
+        assert!(Summary::new(0, &nodes("[->+<]")).fan_in.is_empty());
+
+        let summary = Summary::new(0, &nodes("[->+>+>+>+>+>+<<<<<<]"));
+        assert_eq!(summary.fan_in, [(0, 7)]);
+    }
+
+    #[test]
+    fn diagnostics_report_every_bracket_problem() {
+        let diags = diagnostics("]+[");
+        assert_eq!(diags.len(), 2);
+        assert_eq!(diags[0].range, 0..1);
+        assert_eq!(diags[1].range, 2..3);
+    }
+
+    #[test]
+    fn unmatched_brackets_give_line_and_col() {
+        let err = parse(lex("+\n [".to_string()).unwrap(), false).unwrap_err();
+        assert!(matches!(err, BFE::UnclosedJump { line: 2, col: 2 }));
+
+        let err = parse(lex("+]".to_string()).unwrap(), false).unwrap_err();
+        assert!(matches!(err, BFE::UnexpectedClose { line: 1, col: 2 }));
+    }
+
+    #[test]
+    fn errors_describe_themselves() {
+        let err = BFE::StepLimitExceeded { steps: 10 };
+        assert!(err.to_string().contains("limit of 10 steps"));
+
+        let err = BFE::CellOverflow {
+            pointer: 3,
+            value: 255,
+            delta: 1,
+        };
+        assert!(err.to_string().contains("cell 3 overflowed"));
+    }
+
+    #[test]
+    fn net_zero_nodes_are_elided() {
+        assert!(nodes("+-+-").is_empty());
+        assert_eq!(dump(&nodes("[><+]")), "Block@0\n  Delta(1)\n");
+    }
+
+    #[test]
+    fn concat_programs_coalesces_the_boundary() {
+        let joined = concat_programs(nodes("[++]"), nodes("++"));
+        assert_eq!(dump(&joined), "Block@0\n  Delta(2)\nDelta(2)\n");
+
+        let joined = concat_programs(nodes("[-]++"), nodes("++."));
+        assert_eq!(dump(&joined), "Block@0\n  Delta(-1)\nDelta(4)\nWrite\n");
+
+        // the moves cancel out, which brings the deltas together
+        let joined = concat_programs(nodes("+>"), nodes("<-."));
+        assert_eq!(dump(&joined), "Write\n");
+    }
+
+    #[test]
+    fn reparsing_an_edit_matches_a_full_parse() {
+        let before = "+++[>++<-]\né>.";
+        let tokens = lex(before.to_string()).unwrap();
+        let positions = |tokens: &[Token]| -> Vec<_> { tokens.iter().map(|t| (t.offset, t.line, t.col)).collect() };
+
+        // one character changed, on the first line and then after a wide one
+        for (after, edit) in [("+++[>+-<-]\né>.", 6), ("+++[>++<-]\né<.", 13)] {
+            let (patched, reparsed) = reparse(tokens.clone(), after, edit).unwrap();
+            let full = lex(after.to_string()).unwrap();
+            assert_eq!(positions(&patched), positions(&full));
+            assert_eq!(dump(&reparsed), dump(&parse(full, false).unwrap()));
+        }
+    }
+
+    #[test]
+    fn dump_shows_the_parsed_tree() {
+        let expected = "Delta(3)\nBlock@3\n  Move(1)\n  Delta(1)\n  Move(-1)\n  Delta(-1)\n";
+        assert_eq!(dump(&nodes("+++[>+<-]")), expected);
+    }
+
+    #[test]
+    fn clear_loops_become_set_zero() {
+        let lowered = lower_clear_loops(nodes("+++[-]"));
+        assert_eq!(dump(&lowered), "Delta(3)\nSetZero\n");
+        assert_eq!(run_nodes(State::new(), &lowered).unwrap().cell_at(0), 0);
+    }
+
+    #[test]
+    fn adjacent_clears_become_one_clear_range() {
+        let lowered = lower_clear_ranges(lower_clear_loops(nodes("[-]>[-]>[-]")));
+        assert_eq!(dump(&lowered), "ClearRange(0..=2)\nMove(2)\n");
+
+        let program = nodes("+>++>+++>++++<<<[-]>[-]>[-]<<[-]");
+        let lowered = lower_clear_ranges(lower_clear_loops(program.clone()));
+        assert_eq!(dump(&lowered[7..]), "ClearRange(-3..=-1)\nMove(-3)\n");
+        let plain = run_nodes(State::new(), &program).unwrap();
+        let fast = run_nodes(State::new(), &lowered).unwrap();
+        assert_eq!(plain, fast);
+        assert_eq!(fast.tape(), (vec![], vec![0, 0, 0, 4]));
+
+        // a gap between the clears keeps them apart
+        let lowered = lower_clear_ranges(lower_clear_loops(nodes("[-]>>[-]")));
+        assert_eq!(dump(&lowered), "SetZero\nMove(2)\nSetZero\n");
+    }
+
+    #[test]
+    fn passes_can_be_picked_and_chosen() {
+        let coalescing: Vec<Pass> = passes().into_iter().filter(|pass| pass.name == "coalesce").collect();
+        assert_eq!(dump(&run_passes(nodes("[-]"), &coalescing)), "Block@0\n  Delta(-1)\n");
+        assert_eq!(dump(&run_passes(nodes("[-]"), &passes())), "SetZero\n");
+
+        let built = vec![Node::Move(1), Node::Delta(2), Node::Delta(-2), Node::Move(-1)];
+        assert!(run_passes(built, &coalescing).is_empty());
+    }
+
+    #[test]
+    fn mul_loops_match_unoptimized_loops() {
+        // 3 * 4 into cell 1, then 12 * 5 into cell 2 and 12 * 2 into cell 3
+        let program = nodes("+++[>++++<-]>[>+++++>++<<-]");
+        let lowered = lower_mul_loops(program.clone());
+        assert_eq!(dump(&lowered), "Delta(3)\nMulAdd(1:4)\nMove(1)\nMulAdd(1:5,2:2)\n");
+
+        let plain = run_nodes(State::new(), &program).unwrap();
+        let fast = run_nodes(State::new(), &lowered).unwrap();
+        assert_eq!(plain, fast);
+        assert_eq!(fast.tape(), (vec![], vec![0, 0, 60, 24]));
+
+        // loops with I/O or inner loops are left alone
+        assert_eq!(lower_mul_loops(nodes("[-.>+<]")).len(), 1);
+        assert_eq!(lower_mul_loops(nodes("[->[-]<]"))[0].kind(), "Block");
+    }
+
+    #[test]
+    fn mul_loops_fail_like_loops_at_the_tape_edge() {
+        let src = format!("{}+[->>>>>>>>>>+<<<<<<<<<<]", ">".repeat(32760));
+        let plain = run_nodes(State::new(), &nodes(&src)).unwrap_err().to_string();
+        let lowered = run_nodes(State::new(), &lower_mul_loops(nodes(&src)));
+        assert!(plain.contains("passes the last addressable cell"), "{}", plain);
+        assert_eq!(lowered.unwrap_err().to_string(), plain);
+    }
+
+    #[test]
+    fn scan_loops_run_both_ways_and_grow_the_tape() {
+        let state = run_nodes(State::new(), &lower_scan_loops(nodes("+>+>+<<[>]"))).unwrap();
+        assert_eq!(state.pointer, 3);
+        assert_eq!(state.extents(), (0, 4));
+
+        let state = run_nodes(State::new(), &lower_scan_loops(nodes("+<+<+>>[<]"))).unwrap();
+        assert_eq!(state.pointer, -3);
+        assert_eq!(state.extents(), (3, 1));
+
+        let state = run_nodes(State::new(), &lower_scan_loops(nodes("+>>+>>+<<<<[>>]"))).unwrap();
+        assert_eq!(state.pointer, 6);
+    }
+
+    #[test]
+    fn scan_loops_fail_like_loops_at_the_tape_edge() {
+        for src in [
+            format!("{}+>+>+<<[>]", ">".repeat(32765)),
+            format!("{}+>>+<<[>>]", ">".repeat(32764)),
+        ] {
+            let plain = run_nodes(State::new(), &nodes(&src)).unwrap_err().to_string();
+            let lowered = run_nodes(State::new(), &lower_scan_loops(nodes(&src)));
+            assert!(plain.contains("passes the last addressable cell"), "{}", plain);
+            assert_eq!(lowered.unwrap_err().to_string(), plain);
+        }
+    }
+
+    #[test]
+    fn offset_deltas_fold_moves_and_deltas() {
+        let lowered = lower_offset_deltas(nodes(">+>++<<"));
+        assert_eq!(dump(&lowered), "OffsetDeltas(1:1,2:2;0)\n");
+
+        let state = run_nodes(State::new(), &lowered).unwrap();
+        assert_eq!(state.pointer, 0);
+        assert_eq!(state.tape(), (vec![], vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn offset_deltas_fail_like_moves_at_the_tape_edge() {
+        let src = format!("{}.{}+", ">".repeat(32000), ">".repeat(1000));
+        let plain = run_nodes(State::new(), &nodes(&src)).unwrap_err().to_string();
+        let lowered = run_nodes(State::new(), &lower_offset_deltas(nodes(&src)));
+        assert!(plain.contains("passes the last addressable cell"), "{}", plain);
+        assert_eq!(lowered.unwrap_err().to_string(), plain);
+    }
+
+    #[test]
+    fn loops_test_cells_left_of_zero() {
+        let state = run("<+++[->>+<<]").unwrap();
+        assert_eq!(state.cell_at(-1), 0);
+        assert_eq!(state.cell_at(1), 3);
+    }
+
+    #[test]
+    fn read_grows_the_tape_to_the_left() {
+        let state = State {
+            input: Input::Buffer {
+                bytes: b"A".to_vec(),
+                pos: 0,
+            },
+            ..State::new()
+        };
+        assert_eq!(run_on(state, "<,").unwrap().cell_at(-1), 65);
+    }
+
+    #[test]
+    fn eof_modes_set_the_cell() {
+        for (eof, expected) in [(Eof::Unchanged, 7), (Eof::Zero, 0), (Eof::Max, 255)] {
+            let state = State {
+                eof,
+                input: Input::Buffer { bytes: vec![], pos: 0 },
+                ..State::new()
+            };
+            assert_eq!(run_on(state, "+++++++,").unwrap().cell_at(0), expected);
+        }
+    }
+
+    #[test]
+    fn rle_runs_like_repeated_instructions() {
+        let (_, rle) = parse_rle("+200>3-7").unwrap();
+        let plain = nodes(&format!("{}>>>-------", "+".repeat(200)));
+        assert_eq!(
+            run_nodes(State::new(), &rle).unwrap(),
+            run_nodes(State::new(), &plain).unwrap()
+        );
+
+        // moves too long to merge are kept apart
+        let (_, rle) = parse_rle(">30000>30000").unwrap();
+        assert_eq!(dump(&rle), "Move(30000)\nMove(30000)\n");
+    }
+
+    #[test]
+    fn tape_grows_far_in_both_directions() {
+        let src = format!("{}+{}+", "<".repeat(10000), ">".repeat(20000));
+        let state = run(&src).unwrap();
+        assert_eq!(state.extents(), (10000, 10001));
+        assert_eq!(state.cell_at(-10000), 1);
+        assert_eq!(state.cell_at(10000), 1);
+    }
+
+    #[test]
+    fn loop_polarity_picks_when_loops_run() {
+        assert_eq!(run("[+]").unwrap().cell_at(0), 0);
+
+        let state = State {
+            loop_polarity: LoopPolarity::Zero,
+            ..State::new()
+        };
+        assert_eq!(run_on(state, "[+]").unwrap().cell_at(0), 1);
+    }
+
+    #[test]
+    fn fixed_tape_errors_name_the_pointer_and_bounds() {
+        let state = State {
+            data: Tape::new(CellBits::Eight, 3),
+            fixed_tape: true,
+            ..State::new()
+        };
+        let err = run_on(state, ">>>").unwrap_err().to_string();
+        assert!(err.contains("cell 3"), "{}", err);
+        assert!(err.contains("cells 0 to 2"), "{}", err);
+    }
+
+    #[test]
+    fn bounded_tape_rejects_both_ends() {
+        let bounded = || State {
+            tape_size: Some(10),
+            no_negative: true,
+            ..State::new()
+        };
+        let err = run_on(bounded(), "<").unwrap_err().to_string();
+        assert!(err.contains("left of cell 0"), "{}", err);
+
+        let err = run_on(bounded(), &">".repeat(10)).unwrap_err().to_string();
+        assert!(err.contains("past the end of a tape of 10 cells"), "{}", err);
+        assert!(err.contains("cells 0 to 9"), "{}", err);
+
+        assert_eq!(run_on(bounded(), &">".repeat(9)).unwrap().pointer, 9);
+    }
+
+    #[test]
+    fn wrap_tape_wraps_both_ways() {
+        let ring = || State {
+            data: Tape::new(CellBits::Eight, 5),
+            wrap_tape: Some(5),
+            ..State::new()
+        };
+        assert_eq!(run_on(ring(), "<").unwrap().pointer, 4);
+
+        // write to cell 4 by going left, then come back to it going right
+        let state = run_on(ring(), "<+++>.>>>>.").unwrap();
+        assert_eq!(written(&state), [0, 3]);
+        assert_eq!(state.data.len(), 5);
+    }
+
+    #[test]
+    fn peek_and_poke_leave_the_pointer_alone() {
+        let mut state = run(">+").unwrap();
+        assert_eq!(state.peek(1), 1);
+        assert_eq!(state.peek(-20000), 0);
+        assert_eq!(state.extents(), (0, 2));
+
+        state.poke(-3, 300).unwrap();
+        assert_eq!(state.peek(-3), 44);
+        assert_eq!(state.pointer, 1);
+        assert_eq!(run_on(state, "<<<<[-]").unwrap().peek(-3), 0);
+
+        let mut state = State {
+            tape_size: Some(4),
+            ..State::new()
+        };
+        assert!(state.poke(4, 1).is_err());
+        assert_eq!(state.extents(), (0, 1));
+    }
+
+    #[test]
+    fn step_limit_stops_empty_loops() {
+        let state = State {
+            max_steps: Some(1000),
+            ..State::new()
+        };
+        let state = run_on(state, "+[]").unwrap();
+        assert_eq!(state.stopped_by, Some(Limit::Steps(1000)));
+    }
+
+    #[test]
+    fn step_limited_runs_say_so() {
+        let state = State {
+            max_steps: Some(1000),
+            ..State::new()
+        };
+        let outcome = crate::run(state, &nodes("+[]"));
+        assert!(
+            matches!(outcome, RunOutcome::Limited(Limit::Steps(1000), _)),
+            "{:?}",
+            outcome
+        );
+        assert!(outcome.into_state().unwrap().halted);
+
+        assert!(matches!(
+            crate::run(State::new(), &nodes("+[-]")),
+            RunOutcome::Completed(_)
+        ));
+    }
+
+    #[test]
+    fn pre_eval_hooks_rewrite_the_program() {
+        let mut out = vec![];
+        let mut state = State {
+            output: Output::to(&mut out),
+            ..State::new()
+        };
+        state.rewrite_nodes(|nodes| {
+            let silent = nodes.into_iter().map(|node| match node {
+                Node::Write => Node::Comment(String::new()),
+                node => node,
+            });
+            return silent.collect();
+        });
+        let state = crate::run(state, &nodes("++++++++[>++++++++<-]>+.+."))
+            .into_state()
+            .unwrap();
+        assert_eq!(state.cell_at(1), 66);
+        drop(state);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn loop_limit_stops_runaway_loops() {
+        let state = State {
+            max_loop_iterations: Some(100),
+            ..State::new()
+        };
+        let state = run_on(state, "+[]").unwrap();
+        assert_eq!(state.stopped_by, Some(Limit::LoopIterations(1, 100)));
+    }
+
+    #[test]
+    fn deadline_halts_runaway_loops() {
+        let state = State {
+            deadline: Some(std::time::Instant::now() + std::time::Duration::from_millis(50)),
+            ..State::new()
+        };
+        let state = run_on(state, "+[]").unwrap();
+        assert_eq!(state.stopped_by, Some(Limit::Deadline));
+    }
+
+    #[test]
+    fn writes_raw_bytes() {
+        let src = format!("{}.", "+".repeat(200));
+        assert_eq!(run_str(&src, b"").unwrap(), [200]);
+    }
+
+    #[test]
+    fn crlf_newlines_are_translated() {
+        let mut out = vec![];
+        let mut state = State {
+            newlines: Some(Newlines::Crlf),
+            input: Input::reader(&b"A\nB"[..]),
+            output: Output::to(&mut out),
+            ..State::new()
+        };
+        for node in &nodes(",.,.,.") {
+            state = eval(state, node).unwrap();
+        }
+        drop(state);
+        assert_eq!(out, b"A\r\nB");
+    }
+
+    #[test]
+    fn executions_count_each_kind() {
+        let state = run("++[>+<-]").unwrap();
+        let counts = state.executions.sorted();
+        assert_eq!(
+            counts,
+            [("Delta", 5), ("Move", 4), ("loop iterations", 2), ("Block", 1)]
+        );
+    }
+}
@@ -1,5 +1,9 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+use bfstk::*;
 use snafu::prelude::*;
-use std::io::Read;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
 use std::result;
 use std::time::Instant;
 
@@ -10,339 +14,1839 @@ fn main() {
         std::process::exit(1);
     }
 
-    if let Err(e) = run(flags.unwrap()) {
-        eprintln!("Error: {}", e);
-        std::process::exit(2);
+    let flags = flags.unwrap();
+    let result = match (flags.selftest, flags.watch) {
+        (true, _) => selftest(),
+        (false, true) => watch(&flags),
+        (false, false) => run(&flags),
+    };
+    match result {
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code.into()),
     }
 }
 
-type Result<T> = result::Result<T, BFE>;
-
 #[derive(Debug)]
 struct Flags {
     files: Vec<String>,
     with_memdump: bool,
     with_report: bool,
+    with_echo: bool,
+    check_only: bool,
+    watch: bool,
+    // watch_interval is how many milliseconds --watch waits between polls.
+    watch_interval: u64,
+    selftest: bool,
+    deterministic_time: bool,
+    emit_profile: Option<ProfileFormat>,
+    max_tape_bytes: Option<usize>,
+    sample_every: Option<usize>,
+    run_until_tape: Option<Vec<u8>>,
+    trace: Option<TraceFormat>,
+    preload: Option<String>,
+    with_preload_output: bool,
+    explain_parse: bool,
+    emit: Option<Emit>,
+    source_map: Option<String>,
+    with_runtime: bool,
+    pretty: bool,
+    dump_ast: bool,
+    no_run: bool,
+    loop_polarity: LoopPolarity,
+    input_repeat: Option<Vec<u8>>,
+    newlines: Option<Newlines>,
+    output_protocol: OutputProtocol,
+    assert_final_cell: Option<Cell>,
+    profile_allocations: bool,
+    lang: Lang,
+    max_loop_iterations: Option<u64>,
+    warn_below: Option<i16>,
+    max_steps: Option<usize>,
+    timeout: Option<f64>,
+    fixed_tape: Option<usize>,
+    count_values: bool,
+    benchmark: bool,
+    input_file_list: Option<String>,
+    trace_cells: Option<Vec<i16>>,
+    range: Option<(usize, usize)>,
+    verify_deterministic: bool,
+    metrics_file: Option<String>,
+    overflow: Overflow,
+    lex_only: bool,
+    repeat: usize,
+    report_file: Option<String>,
+    strict_matching: bool,
+    eof: Eof,
+    arg_cells: Option<Vec<u8>>,
+    progress_bar: bool,
+    inline: Option<String>,
+    cell_bits: CellBits,
+    max_program_bytes: Option<usize>,
+    tape_snapshots: Option<String>,
+    snapshot_every: usize,
+    output_record_size: Option<usize>,
+    count_backtracks: bool,
+    flush_extension: bool,
+    tape_size: Option<usize>,
+    no_negative: bool,
+    wrap_tape: Option<usize>,
+    // opt_level picks the optimization passes from OPT_LEVELS.
+    opt_level: usize,
+}
+
+/// OPT_LEVELS lists the optimization passes that each of -O0, -O1 and -O2 run,
+/// when the other flags allow them. -O2 is the default.
+const OPT_LEVELS: [&[&str]; 3] = [
+    &[],
+    &["coalesce", "scan-loops", "clear-loops", "clear-ranges"],
+    &[
+        "coalesce",
+        "scan-loops",
+        "clear-loops",
+        "clear-ranges",
+        "mul-loops",
+        "offset-deltas",
+    ],
+];
+
+/// WRAP_TAPE_MAX is the most cells --wrap-tape allows, which leaves the pointer
+/// room to move past either end before it is wrapped around.
+const WRAP_TAPE_MAX: usize = 16384;
+
+impl Flags {
+    /// bounded_tape is whether the tape has limits that the program may run
+    /// into, making moves past them an error.
+    fn bounded_tape(&self) -> bool {
+        return self.fixed_tape.is_some()
+            || self.max_tape_bytes.is_some()
+            || self.tape_size.is_some()
+            || self.no_negative;
+    }
+
+    /// counts_steps is whether anything counts or reports on the program one
+    /// instruction or loop at a time, which lowered nodes would hide.
+    fn counts_steps(&self) -> bool {
+        return self.trace.is_some()
+            || self.sample_every.is_some()
+            || self.tape_snapshots.is_some()
+            || self.emit_profile.is_some()
+            || self.count_backtracks
+            || self.max_steps.is_some()
+            || self.run_until_tape.is_some()
+            || self.trace_cells.is_some();
+    }
+}
+
+/// Lang selects the frontend used to turn source into nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Lang {
+    // Brainfuck is plain brainfuck, lexed one character at a time.
+    #[default]
+    Brainfuck,
+    // Rle is run-length encoded brainfuck, where an instruction may be
+    // followed by a decimal repeat count, e.g. '+200>50'.
+    Rle,
+}
+
+impl std::str::FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "bf" | "brainfuck" => Ok(Lang::Brainfuck),
+            "bfrle" | "brainfuck-rle" => Ok(Lang::Rle),
+            _ => Err(format!("unknown language '{}', expected 'bf' or 'bfrle'", s)),
+        }
+    }
+}
+
+/// OutputProtocol selects how program output is delimited on stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum OutputProtocol {
+    // Raw writes output bytes as they are produced.
+    #[default]
+    Raw,
+    // Frames buffers output and writes it as length-prefixed frames: a 4-byte
+    // big-endian payload length followed by the payload. A frame is written
+    // after each newline, whenever FRAME_SIZE bytes are buffered, and at the
+    // end of the program.
+    Frames,
+}
+
+impl std::str::FromStr for OutputProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputProtocol::Raw),
+            "frames" => Ok(OutputProtocol::Frames),
+            _ => Err(format!("unknown output protocol '{}', expected 'raw' or 'frames'", s)),
+        }
+    }
+}
+
+/// Emit selects an alternative output. Most are produced instead of running
+/// the program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    // Summary prints static metrics about the parsed program.
+    Summary,
+    // TokensJson prints the lexed tokens, before any coalescing, as a JSON
+    // array of objects with the fields kind and offset, plus char for comments.
+    TokensJson,
+    // MetricsPrometheus runs the programs and then writes their run stats in
+    // the Prometheus text exposition format.
+    MetricsPrometheus,
+    // InterpreterTraceSummary runs the programs unoptimized and then lists
+    // the source positions that were executed most often.
+    InterpreterTraceSummary,
+    // C prints the program transpiled to a standalone C program.
+    C,
+    // Rust prints the program transpiled to a standalone Rust program.
+    Rust,
+    // OptReport prints the node count before and after each optimizer pass
+    // instead of running the program.
+    OptReport,
+}
+
+impl std::str::FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(Emit::Summary),
+            "tokens-json" => Ok(Emit::TokensJson),
+            "metrics-prometheus" => Ok(Emit::MetricsPrometheus),
+            "interpreter-trace-summary" => Ok(Emit::InterpreterTraceSummary),
+            "c" => Ok(Emit::C),
+            "rust" => Ok(Emit::Rust),
+            "opt-report" => Ok(Emit::OptReport),
+            _ => Err(format!(
+                "unknown emit mode '{}', expected one of 'summary', 'tokens-json', 'metrics-prometheus', 'interpreter-trace-summary', 'c', 'rust' or 'opt-report'",
+                s
+            )),
+        }
+    }
+}
+
+/// ProfileFormat selects how loop profiling data is written out after a run.
+#[derive(Debug, Clone, Copy)]
+enum ProfileFormat {
+    // Folded emits one line per loop nesting path, with frames separated by
+    // semicolons and the number of iterations as the sample weight, which is
+    // the input format expected by flamegraph tools.
+    Folded,
+}
+
+impl std::str::FromStr for ProfileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "folded" => Ok(ProfileFormat::Folded),
+            _ => Err(format!("unknown profile format '{}', expected 'folded'", s)),
+        }
+    }
 }
 
 fn parse_args() -> Result<Flags> {
-    let mut args = pico_args::Arguments::from_env();
+    let mut raw: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+    let arg_cells = take_arg_cells(&mut raw)?;
+    let opt_level = take_opt_level(&mut raw)?;
+    let mut args = pico_args::Arguments::from_vec(raw);
     let mut flags = Flags {
         files: Vec::new(),
         with_memdump: args.contains("-d"),
         with_report: args.contains("-r"),
+        with_echo: args.contains("--echo-program"),
+        check_only: args.contains("--check"),
+        watch: args.contains("--watch"),
+        watch_interval: args
+            .opt_value_from_str("--watch-interval")
+            .context(ArgsSnafu)?
+            .unwrap_or(WATCH_INTERVAL_MS),
+        selftest: args.contains("--selftest"),
+        deterministic_time: args.contains("--deterministic-time"),
+        emit_profile: args.opt_value_from_str("--emit-profile").context(ArgsSnafu)?,
+        max_tape_bytes: args.opt_value_from_str("--max-tape-bytes").context(ArgsSnafu)?,
+        tape_size: args.opt_value_from_str("--tape-size").context(ArgsSnafu)?,
+        no_negative: args.contains("--no-negative"),
+        wrap_tape: args.opt_value_from_str("--wrap-tape").context(ArgsSnafu)?,
+        sample_every: args.opt_value_from_str("--sample-every").context(ArgsSnafu)?,
+        run_until_tape: args
+            .opt_value_from_fn("--run-until-tape", parse_hex)
+            .context(ArgsSnafu)?,
+        trace: None,
+        preload: args.opt_value_from_str("--preload").context(ArgsSnafu)?,
+        with_preload_output: args.contains("--preload-output"),
+        explain_parse: args.contains("--explain-parse"),
+        emit: args.opt_value_from_str("--emit").context(ArgsSnafu)?,
+        source_map: args.opt_value_from_str("--source-map").context(ArgsSnafu)?,
+        with_runtime: args.contains("--with-runtime"),
+        pretty: args.contains("--pretty"),
+        dump_ast: args.contains("--dump-ast"),
+        no_run: args.contains("--no-run"),
+        loop_polarity: args
+            .opt_value_from_str("--loop-polarity")
+            .context(ArgsSnafu)?
+            .unwrap_or_default(),
+        input_repeat: args
+            .opt_value_from_fn("--input-repeat", parse_repeat)
+            .context(ArgsSnafu)?,
+        newlines: args.opt_value_from_str("--canonical-newlines").context(ArgsSnafu)?,
+        output_protocol: args
+            .opt_value_from_str("--output-protocol")
+            .context(ArgsSnafu)?
+            .unwrap_or_default(),
+        assert_final_cell: args.opt_value_from_str("--assert-final-cell").context(ArgsSnafu)?,
+        profile_allocations: args.contains("--profile-allocations"),
+        lang: args
+            .opt_value_from_str("--lang")
+            .context(ArgsSnafu)?
+            .unwrap_or_default(),
+        max_loop_iterations: args.opt_value_from_str("--max-loop-iterations").context(ArgsSnafu)?,
+        warn_below: args.opt_value_from_str("--warn-below").context(ArgsSnafu)?,
+        max_steps: args.opt_value_from_str("--max-steps").context(ArgsSnafu)?,
+        timeout: args.opt_value_from_str("--timeout").context(ArgsSnafu)?,
+        fixed_tape: args.opt_value_from_str("--fixed-tape").context(ArgsSnafu)?,
+        count_values: args.contains("--count-distinct-values"),
+        benchmark: args.contains("--benchmark"),
+        input_file_list: args.opt_value_from_str("--input-file-list").context(ArgsSnafu)?,
+        range: args.opt_value_from_fn("--range", parse_range).context(ArgsSnafu)?,
+        trace_cells: args
+            .opt_value_from_fn("--trace-cells", parse_cell_list)
+            .context(ArgsSnafu)?,
+        verify_deterministic: args.contains("--verify-deterministic"),
+        metrics_file: args.opt_value_from_str("--metrics-file").context(ArgsSnafu)?,
+        overflow: args
+            .opt_value_from_str("--overflow")
+            .context(ArgsSnafu)?
+            .unwrap_or_default(),
+        lex_only: args.contains("--lex-only"),
+        repeat: args.opt_value_from_str("--repeat").context(ArgsSnafu)?.unwrap_or(1),
+        report_file: args.opt_value_from_str("--report-file").context(ArgsSnafu)?,
+        strict_matching: args.contains("--strict-matching"),
+        eof: args.opt_value_from_str("--eof").context(ArgsSnafu)?.unwrap_or_default(),
+        arg_cells,
+        opt_level,
+        progress_bar: args.contains("--progress-bar"),
+        inline: None,
+        cell_bits: args
+            .opt_value_from_str("--cell-bits")
+            .context(ArgsSnafu)?
+            .unwrap_or_default(),
+        max_program_bytes: args.opt_value_from_str("--max-program-bytes").context(ArgsSnafu)?,
+        tape_snapshots: args.opt_value_from_str("--tape-snapshots").context(ArgsSnafu)?,
+        snapshot_every: args
+            .opt_value_from_str("--snapshot-every")
+            .context(ArgsSnafu)?
+            .unwrap_or(SNAPSHOT_EVERY),
+        output_record_size: args.opt_value_from_str("--output-record-size").context(ArgsSnafu)?,
+        count_backtracks: args.contains("--count-backtracks"),
+        flush_extension: args.contains("--flush-extension"),
     };
 
+    let trace_format: Option<TraceFormat> = args.opt_value_from_str("--trace-format").context(ArgsSnafu)?;
+    if args.contains("--trace") || trace_format.is_some() {
+        flags.trace = Some(trace_format.unwrap_or(TraceFormat::Text));
+    }
+
+    ensure!(
+        !(matches!(flags.emit, Some(Emit::C | Emit::Rust)) && flags.overflow != Overflow::Wrap),
+        InvalidFlagsSnafu {
+            reason: "--emit=c and --emit=rust only support --overflow=wrap",
+        }
+    );
+    if let Some(n) = flags.wrap_tape {
+        ensure!(
+            (1..=WRAP_TAPE_MAX).contains(&n),
+            InvalidFlagsSnafu {
+                reason: format!("--wrap-tape must be between 1 and {} cells", WRAP_TAPE_MAX),
+            }
+        );
+        ensure!(
+            !flags.bounded_tape(),
+            InvalidFlagsSnafu {
+                reason: "--wrap-tape can't be combined with other tape limits",
+            }
+        );
+    }
+    ensure!(
+        !flags.with_runtime || flags.emit == Some(Emit::C),
+        InvalidFlagsSnafu {
+            reason: "--with-runtime only applies to --emit=c",
+        }
+    );
+    ensure!(
+        flags.source_map.is_none() || matches!(flags.emit, Some(Emit::C | Emit::Rust)),
+        InvalidFlagsSnafu {
+            reason: "--source-map only applies to --emit=c and --emit=rust",
+        }
+    );
+    ensure!(
+        !(flags.source_map.is_some() && flags.lang == Lang::Rle),
+        InvalidFlagsSnafu {
+            reason: "--source-map is not available with --lang=bfrle, which is parsed without positions",
+        }
+    );
+    ensure!(
+        !flags.no_run || flags.dump_ast,
+        InvalidFlagsSnafu {
+            reason: "--no-run only applies to --dump-ast",
+        }
+    );
+    ensure!(
+        !flags.pretty || flags.emit == Some(Emit::TokensJson),
+        InvalidFlagsSnafu {
+            reason: "--pretty only applies to --emit=tokens-json",
+        }
+    );
+    ensure!(
+        !(flags.range.is_some() && flags.lang == Lang::Rle),
+        InvalidFlagsSnafu {
+            reason: "--range is not available with --lang=bfrle, which is parsed without tokens",
+        }
+    );
+    ensure!(
+        !(flags.emit == Some(Emit::TokensJson) && flags.lang == Lang::Rle),
+        InvalidFlagsSnafu {
+            reason: "--emit=tokens-json is not available with --lang=bfrle, which is parsed without tokens",
+        }
+    );
+    ensure!(
+        !(flags.emit == Some(Emit::InterpreterTraceSummary) && flags.lang == Lang::Rle),
+        InvalidFlagsSnafu {
+            reason:
+                "--emit=interpreter-trace-summary is not available with --lang=bfrle, which is parsed without positions",
+        }
+    );
+
+    let seed_from_stdin_hex = args.contains("--seed-from-stdin-hex");
+    // every -e is joined into a single inline program that runs before any
+    // program files
+    let inline: Vec<String> = args.values_from_str(["-e", "--eval"]).context(ArgsSnafu)?;
+    if !inline.is_empty() {
+        flags.inline = Some(inline.concat());
+        flags.files.push(INLINE_PROGRAM.to_string());
+    }
+
+    // with no programs at all the program is read from stdin, as if '-' had
+    // been given
     let rem = args.finish();
-    if !rem.is_empty() {
-        flags.files = rem.iter().map(|s| s.to_string_lossy().to_string()).collect();
+    flags.files.extend(rem.iter().map(|s| s.to_string_lossy().to_string()));
+    if flags.files.is_empty() {
+        flags.files.push(STDIN_PROGRAM.to_string());
+    }
+
+    // a tape seeded from stdin goes where --arg-cells would put it
+    if seed_from_stdin_hex {
+        ensure!(
+            flags.arg_cells.is_none() && !flags.files.iter().any(|f| f == STDIN_PROGRAM),
+            InvalidFlagsSnafu {
+                reason: "--seed-from-stdin-hex needs stdin to itself, so it can't be used with --arg-cells or a program from stdin",
+            }
+        );
+        let mut hex = String::new();
+        std::io::stdin().read_to_string(&mut hex).context(ReadInputSnafu)?;
+        hex.retain(|c| !c.is_whitespace());
+        let seed = parse_hex(&hex).map_err(|reason| BFE::InvalidFlags {
+            reason: format!("--seed-from-stdin-hex: {}", reason),
+        })?;
+        flags.arg_cells = Some(seed);
+    }
+
+    let sizes = [
+        ("--fixed-tape", flags.fixed_tape.map(|size| size.max(1))),
+        ("--tape-size", flags.tape_size),
+        ("--wrap-tape", flags.wrap_tape),
+    ];
+    for (flag, size) in sizes {
+        if let (Some(cells), Some(size)) = (&flags.arg_cells, size) {
+            ensure!(
+                cells.len() <= size,
+                InvalidFlagsSnafu {
+                    reason: format!(
+                        "the tape seed sets {} cells, but {} only has {}",
+                        cells.len(),
+                        flag,
+                        size
+                    ),
+                }
+            );
+        }
     }
 
     return Ok(flags);
 }
 
-fn run(flags: Flags) -> Result<u8> {
-    for filename in flags.files {
-        let mut ts = vec![("start", Instant::now())];
+/// take_arg_cells removes --arg-cells and the decimal byte values following it
+/// from the raw arguments. The values are separate arguments, which pico_args
+/// can't express, so they are taken out before it sees them; the first argument
+/// that isn't a number ends the list.
+fn take_arg_cells(raw: &mut Vec<std::ffi::OsString>) -> Result<Option<Vec<u8>>> {
+    let Some(start) = raw.iter().position(|a| a == "--arg-cells") else {
+        return Ok(None);
+    };
 
-        let content = std::fs::read_to_string(filename.clone()).context(FileLoadSnafu {
-            filename: filename.clone(),
+    let mut cells = vec![];
+    let mut end = start + 1;
+    while let Some(value) = raw.get(end).and_then(|a| a.to_str()?.parse::<i64>().ok()) {
+        let cell = u8::try_from(value).ok().context(InvalidFlagsSnafu {
+            reason: format!("--arg-cells value {} is not within 0..=255", value),
         })?;
-        ts.push(("read", Instant::now()));
+        cells.push(cell);
+        end += 1;
+    }
+    ensure!(
+        !cells.is_empty(),
+        InvalidFlagsSnafu {
+            reason: "--arg-cells needs at least one value",
+        }
+    );
+
+    raw.drain(start..end);
+    return Ok(Some(cells));
+}
+
+/// take_opt_level removes any -O0, -O1 or -O2 from the raw arguments, which
+/// pico_args can't take as they aren't single-character flags, and returns the
+/// level of the last one.
+fn take_opt_level(raw: &mut Vec<std::ffi::OsString>) -> Result<usize> {
+    let mut level = OPT_LEVELS.len() - 1;
+    for arg in raw.iter().filter_map(|a| a.to_str()?.strip_prefix("-O")) {
+        level = arg
+            .parse()
+            .ok()
+            .filter(|&n| n < OPT_LEVELS.len())
+            .context(InvalidFlagsSnafu {
+                reason: format!("unknown optimization level '-O{}', expected -O0, -O1 or -O2", arg),
+            })?;
+    }
+    raw.retain(|a| !a.to_str().is_some_and(|a| a.starts_with("-O")));
+    return Ok(level);
+}
+
+/// parse_repeat takes the bytes of a non-empty string as input to cycle.
+fn parse_repeat(s: &str) -> result::Result<Vec<u8>, String> {
+    if s.is_empty() {
+        return Err("input to repeat must not be empty".to_string());
+    }
+    return Ok(s.as_bytes().to_vec());
+}
+
+/// parse_cell_list parses a comma-separated list of cell offsets.
+fn parse_cell_list(s: &str) -> result::Result<Vec<i16>, String> {
+    return s
+        .split(',')
+        .map(|c| c.trim().parse().map_err(|e| format!("'{}': {}", c, e)))
+        .collect();
+}
+
+/// parse_range parses a range of byte offsets given as START:END, where END
+/// is exclusive.
+fn parse_range(s: &str) -> result::Result<(usize, usize), String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not a range of the form START:END", s))?;
+    let start: usize = start.trim().parse().map_err(|e| format!("'{}': {}", start, e))?;
+    let end: usize = end.trim().parse().map_err(|e| format!("'{}': {}", end, e))?;
+    if start > end {
+        return Err(format!("range '{}' ends before it starts", s));
+    }
+    return Ok((start, end));
+}
+
+/// parse_hex decodes a string of hexadecimal digit pairs into bytes.
+fn parse_hex(s: &str) -> result::Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("'{}' is not a sequence of hex byte pairs", s));
+    }
+
+    return (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect();
+}
 
-        let tokens = lex(content)?;
-        ts.push(("lex", Instant::now()));
+/// load reads the program source from a local file or, when built with the
+/// `http` feature, from an http:// URL.
+fn load(filename: &str) -> Result<String> {
+    if filename.starts_with("http://") || filename.starts_with("https://") {
+        return fetch(filename);
+    }
+
+    return std::fs::read_to_string(filename).context(FileLoadSnafu { filename });
+}
 
-        let nodes = parse(tokens)?;
-        ts.push(("parse", Instant::now()));
+/// FETCH_TIMEOUT is how long fetch waits to connect to a server, and then for
+/// each read or write, before giving up on it.
+#[cfg(feature = "http")]
+const FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// fetch downloads the program source with a bare-bones HTTP/1.0 GET, which
+/// avoids having to deal with chunked transfer encoding. TLS is not supported,
+/// so https:// URLs are refused with an error saying so.
+#[cfg(feature = "http")]
+fn fetch(url: &str) -> Result<String> {
+    use std::io::Write;
+    use std::net::ToSocketAddrs;
+
+    let rest = url.strip_prefix("http://").context(FetchUrlSnafu {
+        url,
+        reason: "https:// needs TLS, which bfstk doesn't support; use an http:// URL",
+    })?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
 
-        let mut state = State::new();
-        for node in nodes {
-            state = eval(state, node)?;
+    // try every address the host resolves to, keeping the last error
+    let mut result = Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+    for addr in addr.to_socket_addrs().context(FetchSnafu { url })? {
+        result = std::net::TcpStream::connect_timeout(&addr, FETCH_TIMEOUT);
+        if result.is_ok() {
+            break;
         }
-        ts.push(("eval", Instant::now()));
+    }
+    let mut stream = result.context(FetchSnafu { url })?;
+    stream
+        .set_read_timeout(Some(FETCH_TIMEOUT))
+        .context(FetchSnafu { url })?;
+    stream
+        .set_write_timeout(Some(FETCH_TIMEOUT))
+        .context(FetchSnafu { url })?;
+    // the request goes out in one write, so that a server replying as soon as
+    // it has read the request line doesn't see the rest arrive after it
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+    stream.write_all(request.as_bytes()).context(FetchSnafu { url })?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).context(FetchSnafu { url })?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").context(FetchUrlSnafu {
+        url,
+        reason: "malformed HTTP response",
+    })?;
+    let status = head.lines().next().unwrap_or_default();
+    ensure!(
+        status
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|code| code.starts_with('2')),
+        FetchUrlSnafu {
+            url,
+            reason: format!("server responded with '{}'", status),
+        }
+    );
+
+    return Ok(body.to_string());
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch(url: &str) -> Result<String> {
+    return FetchUrlSnafu {
+        url,
+        reason: "bfstk was built without the 'http' feature",
+    }
+    .fail();
+}
+
+/// WATCH_INTERVAL_MS is how often --watch polls the programs for changes,
+/// unless --watch-interval says otherwise.
+const WATCH_INTERVAL_MS: u64 = 250;
 
-        if flags.with_report {
-            eprintln!("State:");
-            eprintln!("  counter: {}", state.counter);
-            eprintln!("  memory: {} {}", state.data_left.len(), state.data_right.len());
+/// SELFTESTS are the programs run by --selftest, each with a name, the input
+/// it is given and the output it must produce.
+const SELFTESTS: [(&str, &str, &[u8], &[u8]); 3] = [
+    (
+        "hello world",
+        include_str!("../examples/hello_world.b"),
+        b"",
+        b"Hello World!\n",
+    ),
+    // run_program reads EOF as 0, which is what stops cat
+    ("cat", ",[.,]", b"meow", b"meow"),
+    ("arithmetic", include_str!("../examples/math.b"), b"", b"7"),
+];
 
-            eprintln!("Timings:");
-            for t in ts.windows(2) {
-                eprintln!("  {}: {:.2?}", &t[1].0, &t[1].1.duration_since(t[0].1));
+/// selftest runs the built-in programs and reports whether each produced the
+/// output it should, exiting with 1 if any didn't.
+fn selftest() -> Result<u8> {
+    let mut failed = 0;
+    for (name, program, input, expected) in SELFTESTS {
+        let mut output = vec![];
+        // the final state borrows output, so let go of it before comparing
+        let result = run_program(program, input, &mut output).map(|_| ());
+        match result {
+            Ok(_) if output == expected => println!("selftest {}: ok", name),
+            Ok(_) => {
+                failed += 1;
+                println!(
+                    "selftest {}: FAILED, expected {:?} and got {:?}",
+                    name,
+                    String::from_utf8_lossy(expected),
+                    String::from_utf8_lossy(&output)
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("selftest {}: FAILED, {}", name, e);
             }
-        }
-        if flags.with_memdump {
-            eprintln!("Memory dump:");
-            eprintln!(" left: {:?}", state.data_left);
-            eprintln!(" left: {:?}", state.data_right);
         }
     }
+    println!("selftest: {} of {} passed", SELFTESTS.len() - failed, SELFTESTS.len());
+    return Ok(if failed > 0 { 1 } else { 0 });
+}
 
-    return Ok(0);
+/// watch runs the programs, then polls their modification times and runs them
+/// again whenever any of them changes, clearing the terminal in between if
+/// stdout is one. Errors are reported but don't stop watching.
+fn watch(flags: &Flags) -> Result<u8> {
+    let interval = std::time::Duration::from_millis(flags.watch_interval.max(1));
+    let clear = std::io::stdout().is_terminal();
+    let mtimes = || -> Vec<Option<std::time::SystemTime>> {
+        return flags
+            .files
+            .iter()
+            .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect();
+    };
+
+    loop {
+        let last = mtimes();
+        if let Err(e) = run(flags) {
+            eprintln!("Error: {}", e);
+        }
+        std::io::stdout().flush().context(WriteOutputSnafu)?;
+
+        while mtimes() == last {
+            std::thread::sleep(interval);
+        }
+        if clear {
+            print!("\x1b[2J\x1b[H");
+        }
+    }
 }
 
-#[derive(Debug, Snafu)]
-enum BFE {
-    #[snafu(display("cannot load file '{filename}'"))]
-    FileLoad {
-        source: std::io::Error,
-        filename: String,
-    },
-    #[snafu(display("stack underflow: {reason}"))]
-    StackUnderflow {
-        reason: String,
-    },
-    UnclosedJump,
-    #[snafu(display("x"))]
-    ReadInput {
-        source: std::io::Error,
-    },
-    #[snafu(display("BUG! internal invariant violated: {reason}"))]
-    InvariantViolation {
-        reason: String,
-    },
-    Unknown,
-}
-
-#[derive(Debug, Clone)]
-struct Token {
-    kind: TokenKind,
-}
-
-#[derive(Debug, Clone)]
-enum TokenKind {
-    Comment(char),
-
-    DecrementByte, // -
-    IncrementByte, // +
-
-    MoveRight, // >
-    MoveLeft,  // <
-
-    Input,  // ,
-    Output, // .
-
-    JumpRight, // [ // also jump-if-zero
-    JumpLeft,  // ] // also jump-if-nonzero
-}
-
-/// lex scans through the input and coverts each character into a token. No
-/// transformation happens at this step.
-fn lex(content: String) -> Result<Vec<Token>> {
-    let mut toks = Vec::with_capacity(content.len());
-    for ch in content.chars() {
-        let kind = match ch {
-            '-' => TokenKind::DecrementByte,
-            '+' => TokenKind::IncrementByte,
-            '>' => TokenKind::MoveRight,
-            '<' => TokenKind::MoveLeft,
-            ',' => TokenKind::Input,
-            '.' => TokenKind::Output,
-            '[' => TokenKind::JumpRight,
-            ']' => TokenKind::JumpLeft,
-            c => TokenKind::Comment(c),
-        };
+/// STDIN_PROGRAM is the pseudo-filename that reads the program from stdin.
+const STDIN_PROGRAM: &str = "-";
+
+/// INLINE_PROGRAM is the pseudo-filename of the program given with -e.
+const INLINE_PROGRAM: &str = "-e";
 
-        toks.push(Token { kind });
-    }
-    return Ok(toks);
-}
-
-/// Node represents a node that could have been combined from one or more tokens.
-#[derive(Debug, Clone)]
-enum Node {
-    // Comment is a comment string, which in brainfuck could be anything that
-    // isn't an instruction.
-    Comment(String),
-    // Delta represents a series of one or more increments and/or decrements
-    // in a row. By convention, net positive increments results in a positive
-    // delta value, and net positive decrements results in a negative delta value.
-    // Net zeros are not yet elided.
-    Delta(i8),
-    // Move represents a series of one or more cell moves left or right. By
-    // convention, moves right have positive values, while moves left have
-    // negative values.
-    Move(i16),
-    // Read is an instruction to read one u8 character from STDIN.
-    Read,
-    // Write is an instruction to write one u8 character to STDOUT.
-    Write,
-    // Block is a list of parsed nodes from between a JumpRight and JumpLeft
-    // pair of tokens.
-    Block(Vec<Node>),
-}
-
-/// parse runs through the list of tokens, coalescing similar tokens in a row
-/// if they are safe to combine, and emits a list of parsed nodes.
-fn parse(tokens: Vec<Token>) -> Result<Vec<Node>> {
-    let mut spans: Vec<Vec<Node>> = vec![vec![]];
-    let mut span = spans.last_mut().context(InvariantViolationSnafu {
-        reason: "expecting 'spans' stack to not be empty",
+/// load_stdin_program reads a program from stdin. Since the program's own input
+/// would also come from stdin, anything after the first '!' is split off and
+/// returned as the program's input instead.
+fn load_stdin_program() -> Result<(String, Option<Vec<u8>>)> {
+    let mut content = String::new();
+    std::io::stdin().read_to_string(&mut content).context(FileLoadSnafu {
+        filename: STDIN_PROGRAM,
     })?;
 
-    for token in tokens {
-        match token.kind {
-            // a comment can be combined into the same comment node, when the
-            // previous token was also a comment
-            TokenKind::Comment(b) => match span.last_mut() {
-                Some(Node::Comment(a)) => a.push(b),
-                _ => span.push(Node::Comment(b.to_string())),
-            },
-
-            // a decrement or an increment can be combined when the previous
-            // node was a delta, which can only happen if the token was also
-            // either a decrement or an increment
-            TokenKind::DecrementByte => match span.last_mut() {
-                Some(Node::Delta(a)) => {
-                    *a -= 1;
-                }
-                _ => {
-                    span.push(Node::Delta(-1));
-                }
-            },
-            TokenKind::IncrementByte => match span.last_mut() {
-                Some(Node::Delta(a)) => {
-                    *a += 1;
-                }
-                _ => {
-                    span.push(Node::Delta(1));
-                }
-            },
-
-            // moves right or left can be combined when the previous node
-            // was a move, which only happen if the previous token was also
-            // either a move right or left
-            TokenKind::MoveRight => match span.last_mut() {
-                Some(Node::Move(a)) => {
-                    *a += 1;
-                }
-                _ => {
-                    span.push(Node::Move(1));
-                }
-            },
-            TokenKind::MoveLeft => match span.last_mut() {
-                Some(Node::Move(a)) => {
-                    *a -= 1;
-                }
-                _ => {
-                    span.push(Node::Move(-1));
-                }
-            },
+    match content.split_once('!') {
+        Some((program, input)) => return Ok((program.to_string(), Some(input.as_bytes().to_vec()))),
+        None => return Ok((content, None)),
+    }
+}
+
+/// load_input_file_list reads a list mapping programs to the file that their
+/// input should be read from. Each line holds a program path and an input
+/// path separated by whitespace; blank lines and lines starting with '#' are
+/// skipped.
+fn load_input_file_list(filename: &str) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(filename).context(FileLoadSnafu { filename })?;
 
-            TokenKind::Input => span.push(Node::Read),
-            TokenKind::Output => span.push(Node::Write),
+    let mut inputs = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
-            TokenKind::JumpRight => {
-                spans.push(vec![]);
-                span = spans.last_mut().context(InvariantViolationSnafu {
-                    reason: "expecting 'spans' stack to not be empty when encountering JumpRight token",
-                })?;
+        let (program, input) = line
+            .split_once(char::is_whitespace)
+            .context(InvalidInputListSnafu { filename, line: i + 1 })?;
+        inputs.insert(program.to_string(), input.trim().to_string());
+    }
+    return Ok(inputs);
+}
+
+/// execute runs the prologue, if any, and then the program against the state,
+/// flushing any buffered output at the end.
+fn execute<'io>(mut state: State<'io>, flags: &Flags, prologue: &[Node], nodes: &[Node]) -> RunOutcome<'io> {
+    let muted = state.muted;
+    state.muted = muted || !flags.with_preload_output;
+    for node in prologue {
+        state = match eval(state, node) {
+            Ok(state) => state,
+            Err(e) => return RunOutcome::Failed(e),
+        };
+        if state.halted {
+            break;
+        }
+    }
+    state.muted = muted;
+    return bfstk::run(state, nodes);
+}
+
+/// limit_error describes the limit that stopped a program as the error the
+/// run exits with.
+fn limit_error(flags: &Flags, filename: &str, limit: Limit) -> BFE {
+    match limit {
+        Limit::Steps(steps) => return BFE::StepLimitExceeded { steps },
+        Limit::LoopIterations(offset, limit) => return BFE::LoopLimitExceeded { offset, limit },
+        Limit::Deadline => {
+            return BFE::Timeout {
+                filename: filename.to_string(),
+                seconds: flags.timeout.unwrap_or_default(),
             }
-            TokenKind::JumpLeft => match spans.pop() {
-                None => {
-                    return Err(BFE::StackUnderflow {
-                        reason: "expecting 'spans' stack to not be empty when encountering JumpLeft token (None case)"
-                            .to_string(),
-                    })
-                }
-                Some(prev) => {
-                    span = spans.last_mut().context(StackUnderflowSnafu {
-                        reason: "found closing jump-if-nonzero ']' without a corresponding opening jump-if-zero '['",
-                    })?;
-                    span.push(Node::Block(prev));
+        }
+    }
+}
+
+fn run(flags: &Flags) -> Result<u8> {
+    let prologue = match &flags.preload {
+        Some(filename) => optimize(
+            flags,
+            parse(extend_tokens(flags, lex(load(filename)?)?), flags.explain_parse)?,
+        ),
+        None => vec![],
+    };
+    let inputs = match &flags.input_file_list {
+        Some(filename) => load_input_file_list(filename)?,
+        None => HashMap::new(),
+    };
+    let mut metrics = vec![];
+
+    let mut progress = flags.progress_bar.then(|| Progress::new(flags.files.len()));
+    for filename in &flags.files {
+        let result = run_file(flags, filename, &prologue, &inputs, &mut metrics);
+        match progress.as_mut() {
+            None => result?,
+            Some(progress) => {
+                if let Err(e) = &result {
+                    progress.clear();
+                    eprintln!("Error: {}: {}", filename, e);
                 }
-            },
+                progress.update(result.is_ok());
+            }
         }
     }
 
-    if spans.len() > 1 {
-        return Err(BFE::StackUnderflow {
-            reason: "found jump-if-zero '[' that was not closed with a jump-if-nonzero ']'".to_string(),
-        });
+    if flags.emit == Some(Emit::MetricsPrometheus) {
+        let exposition = RunMetrics::exposition(&metrics);
+        match &flags.metrics_file {
+            Some(filename) => std::fs::write(filename, exposition).context(FileWriteSnafu { filename })?,
+            None => std::io::stdout()
+                .write_all(exposition.as_bytes())
+                .context(WriteOutputSnafu)?,
+        }
     }
 
-    let f = spans.first().context(InvariantViolationSnafu {
-        reason: "expecting 'spans' stack to not be empty at end of parsing",
-    })?;
-    return Ok(f.clone());
+    if let Some(progress) = progress {
+        progress.finish();
+        if progress.failed > 0 {
+            return Ok(1);
+        }
+    }
+    return Ok(0);
 }
 
-#[derive(Debug, Clone)]
-struct State {
-    counter: usize,
-    pointer: i16,
-    data_right: Vec<u8>,
-    data_left: Vec<u8>,
+/// Progress reports how far a run over many programs has got on stderr. On a
+/// terminal the line is redrawn in place, otherwise a line is logged for each
+/// program.
+struct Progress {
+    total: usize,
+    passed: usize,
+    failed: usize,
+    tty: bool,
 }
 
-impl State {
-    fn new() -> State {
-        return State {
-            counter: 0,
-            pointer: 0,
-            data_right: vec![0],
-            data_left: vec![],
+impl Progress {
+    fn new(total: usize) -> Progress {
+        return Progress {
+            total,
+            passed: 0,
+            failed: 0,
+            tty: std::io::stderr().is_terminal(),
         };
     }
+
+    /// update counts one more finished program and redraws the progress.
+    fn update(&mut self, passed: bool) {
+        match passed {
+            true => self.passed += 1,
+            false => self.failed += 1,
+        }
+
+        let line = format!(
+            "progress: {}/{} files, {} passed, {} failed",
+            self.passed + self.failed,
+            self.total,
+            self.passed,
+            self.failed
+        );
+        match self.tty {
+            true => eprint!("\r\x1b[K{}", line),
+            false => eprintln!("{}", line),
+        }
+    }
+
+    /// clear erases the progress line from a terminal so that other messages
+    /// can be printed.
+    fn clear(&self) {
+        if self.tty {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    /// finish ends the progress line on a terminal.
+    fn finish(&self) {
+        if self.tty {
+            eprintln!();
+        }
+    }
+}
+
+/// HOT_POSITIONS is how many of the most executed positions are listed by
+/// --emit=interpreter-trace-summary.
+const HOT_POSITIONS: usize = 10;
+
+/// node_offsets maps the address of every node to the source offset it
+/// starts at, walking the nodes alongside their positions.
+fn node_offsets(nodes: &[Node], positions: &[Position], offsets: &mut HashMap<usize, usize>) {
+    for (node, position) in nodes.iter().zip(positions) {
+        offsets.insert(node as *const Node as usize, position.offset);
+        if let Node::Block(_, body) = node {
+            node_offsets(body, &position.body, offsets);
+        }
+    }
+}
+
+/// hot_positions_report lists the source positions whose nodes ran most often,
+/// given how many times the node at each address ran. Nodes merged from
+/// several tokens, such as '+++', are listed at their first token.
+fn hot_positions_report(source: &str, offsets: &HashMap<usize, usize>, hot: &HashMap<usize, usize>) -> String {
+    let mut counts: Vec<(usize, usize)> = hot
+        .iter()
+        .filter_map(|(node, &count)| Some((*offsets.get(node)?, count)))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut out = "Hot positions:\n".to_string();
+    for (offset, count) in counts.into_iter().take(HOT_POSITIONS) {
+        let (line, col) = line_col(source, offset);
+        let symbol = source[offset..].chars().next().unwrap_or(' ');
+        out.push_str(&format!("  {}:{} '{}': {}\n", line, col, symbol, count));
+    }
+    return out;
 }
 
-fn eval(mut state: State, node: Node) -> Result<State> {
-    match node {
-        Node::Comment(_) => {}
+/// run_file runs a single program from the command line with the given
+/// prologue, adding its stats to metrics when they are being collected.
+fn run_file(
+    flags: &Flags,
+    filename: &String,
+    prologue: &[Node],
+    inputs: &HashMap<String, String>,
+    metrics: &mut Vec<RunMetrics>,
+) -> Result<()> {
+    let mut ts = vec![("start", Instant::now())];
 
-        Node::Delta(i) if state.pointer < 0 => {
-            state.counter += 1;
-            state.data_left[-state.pointer as usize] = state.data_left[-state.pointer as usize].wrapping_add_signed(i)
+    // an inline program isn't read from anywhere, so it has no read timing
+    let (content, stdin_input) = match filename.as_str() {
+        INLINE_PROGRAM => (flags.inline.clone().unwrap_or_default(), None),
+        STDIN_PROGRAM => load_stdin_program()?,
+        _ => (load(filename)?, None),
+    };
+    if filename != INLINE_PROGRAM {
+        ts.push(("read", Instant::now()));
+    }
+    if let Some(limit) = flags.max_program_bytes {
+        ensure!(
+            content.len() <= limit,
+            ProgramTooLargeSnafu {
+                filename,
+                size: content.len(),
+                limit,
+            }
+        );
+    }
+
+    if flags.check_only {
+        let diags = diagnostics(&content);
+        for d in &diags {
+            let (line, col) = line_col(&content, d.range.start);
+            eprintln!("{}:{}:{}: {}", filename, line, col, d.message);
+        }
+        ensure!(
+            diags.is_empty(),
+            CheckFailedSnafu {
+                filename: filename.clone(),
+                count: diags.len(),
+            }
+        );
+        return Ok(());
+    }
+
+    if flags.with_echo {
+        eprintln!("Program {}:", filename);
+        eprint!("{}", content);
+        if !content.ends_with('\n') {
+            eprintln!();
         }
-        Node::Delta(i) => {
-            state.counter += 1;
-            state.data_right[state.pointer as usize] = state.data_right[state.pointer as usize].wrapping_add_signed(i);
+    }
+
+    if flags.lex_only {
+        // time only the lexing itself, not copying the source for each
+        // repetition
+        let mut elapsed = std::time::Duration::ZERO;
+        let mut count = 0;
+        for _ in 0..flags.repeat.max(1) {
+            let content = content.clone();
+            let start = Instant::now();
+            count = lex(content)?.len();
+            elapsed += start.elapsed();
         }
+        eprintln!("Lex {}:", filename);
+        eprintln!("  tokens: {}", count);
+        eprintln!("  repetitions: {}", flags.repeat.max(1));
+        eprintln!(
+            "  lex: {:.2?} ({:.2?} each)",
+            elapsed,
+            elapsed / flags.repeat.max(1) as u32
+        );
+        return Ok(());
+    }
 
-        Node::Move(i) => {
-            state.counter += 1;
-            state.pointer += i;
-            if state.pointer < 0 {
-                while (-state.pointer) as usize >= state.data_left.len() {
-                    state.data_left.push(0u8);
-                }
-            } else {
-                while state.pointer as usize >= state.data_right.len() {
-                    state.data_right.push(0u8);
-                }
+    if flags.strict_matching && flags.lang == Lang::Brainfuck {
+        check_matching(filename, &content)?;
+    }
+
+    let hot_positions = flags.emit == Some(Emit::InterpreterTraceSummary);
+    // both of these need every node to map back to the source it came from
+    let mapped = hot_positions || flags.source_map.is_some();
+    // only a bounded tape can be moved out of, which is reported by position
+    let bounded = flags.bounded_tape();
+    let source = (mapped || bounded).then(|| content.clone());
+    let mut positions = vec![];
+    let (instructions, nodes) = match flags.lang {
+        Lang::Brainfuck => {
+            let mut tokens = extend_tokens(flags, lex(content)?);
+            if let Some((start, end)) = flags.range {
+                tokens.retain(|t| (start..end).contains(&t.offset));
+            }
+            ts.push(("lex", Instant::now()));
+            if flags.emit == Some(Emit::TokensJson) {
+                println!("{}", tokens_json(&tokens, flags.pretty));
+                return Ok(());
+            }
+
+            let instructions = tokens
+                .iter()
+                .filter(|t| !matches!(t.kind, TokenKind::Comment(_)))
+                .count();
+            let (nodes, p) = parse_positioned(tokens, flags.explain_parse)?;
+            positions = p;
+            (instructions, nodes)
+        }
+        Lang::Rle => parse_rle(&content)?,
+    };
+    if flags.dump_ast {
+        for node in &nodes {
+            eprint!("{}", node);
+        }
+        if flags.no_run {
+            return Ok(());
+        }
+    }
+    // the summary describes the program as written, before loops are lowered
+    if flags.emit == Some(Emit::Summary) {
+        print!("{}", Summary::new(instructions, &nodes));
+        return Ok(());
+    }
+    if flags.emit == Some(Emit::OptReport) {
+        print!("{}", opt_report(flags, nodes));
+        return Ok(());
+    }
+    // optimized nodes no longer line up with the source they came from
+    let nodes = match mapped {
+        true => nodes,
+        false => optimize(flags, nodes),
+    };
+    ts.push(("parse", Instant::now()));
+
+    if let Some(emit @ (Emit::C | Emit::Rust)) = flags.emit {
+        // positions only line up with the nodes when they weren't optimized
+        let positions = match flags.source_map {
+            Some(_) => &positions[..],
+            None => &[],
+        };
+        let mut map = vec![];
+        let code = match emit {
+            Emit::C => emit_c(&nodes, flags, positions, &mut map),
+            _ => emit_rust(&nodes, flags, positions, &mut map),
+        };
+        print!("{}", code);
+        if let (Some(filename), Some(source)) = (&flags.source_map, &source) {
+            std::fs::write(filename, source_map(&code, source, &map)).context(FileWriteSnafu { filename })?;
+        }
+        return Ok(());
+    }
+
+    let mut state = State::new();
+    if flags.emit_profile.is_some() {
+        state.profile = Some(Profile::default());
+    }
+    state.max_tape_bytes = flags.max_tape_bytes;
+    state.tape_size = flags.tape_size;
+    state.no_negative = flags.no_negative;
+    if let Some(n) = flags.sample_every.filter(|&n| n > 0) {
+        state.sample(n, |state| {
+            eprintln!("sample: counter={} pointer={}", state.counter, state.pointer);
+        });
+    }
+    state.until_tape = flags.run_until_tape.clone();
+    state.trace = flags.trace;
+    state.loop_polarity = flags.loop_polarity;
+    state.overflow = flags.overflow;
+    state.data = Tape::new(flags.cell_bits, 1);
+    if hot_positions {
+        state.hot = Some(HashMap::new());
+    }
+    state.output_record_size = flags.output_record_size;
+    if let Some(dir) = &flags.tape_snapshots {
+        std::fs::create_dir_all(dir).context(FileWriteSnafu { filename: dir })?;
+        state.snapshots = Some(Snapshots {
+            dir: dir.clone(),
+            every: flags.snapshot_every.max(1),
+            taken: 0,
+            last: 0,
+        });
+    }
+    state.eof = flags.eof;
+    state.newlines = flags.newlines;
+    state.max_loop_iterations = flags.max_loop_iterations;
+    state.warn_below = flags.warn_below;
+    state.max_steps = flags.max_steps;
+    if let Some(seconds) = flags.timeout {
+        state.deadline = Some(Instant::now() + std::time::Duration::from_secs_f64(seconds));
+    }
+    if let Some(cells) = &flags.trace_cells {
+        state.watched = cells.iter().map(|&c| (c, state.cell_at(c))).collect();
+    }
+    if let (true, Some(source)) = (bounded, &source) {
+        let mut offsets = HashMap::new();
+        node_offsets(&nodes, &positions, &mut offsets);
+        let locations = offsets
+            .into_iter()
+            .map(|(node, offset)| (node, line_col(source, offset)));
+        state.locations = Some(locations.collect());
+    }
+    if let Some(n) = flags.wrap_tape {
+        state.data = Tape::new(flags.cell_bits, n);
+        state.wrap_tape = Some(n);
+    }
+    if let Some(size) = flags.fixed_tape {
+        state.data = Tape::new(flags.cell_bits, size.max(1));
+        state.fixed_tape = true;
+    }
+    if let Some(cells) = &flags.arg_cells {
+        if state.data.len() < cells.len() {
+            state.data.resize(cells.len());
+        }
+        for (i, &value) in cells.iter().enumerate() {
+            state.data.set(i, value.into());
+        }
+    }
+    if flags.output_protocol == OutputProtocol::Frames {
+        state.frame = Some(Vec::with_capacity(FRAME_SIZE));
+    }
+    if let Some(bytes) = &flags.input_repeat {
+        state.input = Input::Repeat {
+            bytes: bytes.clone(),
+            pos: 0,
+        };
+    }
+    if let Some(bytes) = stdin_input {
+        state.input = Input::Buffer { bytes, pos: 0 };
+    }
+    if let Some(input) = inputs.get(filename) {
+        let bytes = std::fs::read(input).context(FileLoadSnafu { filename: input })?;
+        state.input = Input::Buffer { bytes, pos: 0 };
+    }
+
+    // a limit stops the program without failing it, so that the reports below
+    // still cover the partial run; it is reported as an error after them
+    let limit;
+    if flags.verify_deterministic {
+        // the shadow run goes first so that it can't consume input meant
+        // for the real run, and stays quiet so that it isn't noticed
+        state.input = state.input.buffered()?;
+        let mut shadow = state.clone();
+        shadow.muted = true;
+        shadow.trace = None;
+        shadow.sample_every = None;
+        shadow.watched.clear();
+        shadow.warn_below = None;
+        shadow.snapshots = None;
+        shadow.record = Some(vec![]);
+        state.record = Some(vec![]);
+
+        let shadow = execute(shadow, flags, prologue, &nodes).into_state()?;
+        let outcome = execute(state, flags, prologue, &nodes);
+        limit = outcome.limit();
+        state = outcome.into_state()?;
+        ensure!(
+            shadow.record == state.record,
+            NondeterministicSnafu {
+                filename: filename.clone(),
+                reason: "output differs between runs",
+            }
+        );
+        ensure!(
+            shadow.pointer == state.pointer && shadow.cells().eq(state.cells()),
+            NondeterministicSnafu {
+                filename: filename.clone(),
+                reason: "final tape differs between runs",
+            }
+        );
+    } else {
+        let outcome = execute(state, flags, prologue, &nodes);
+        limit = outcome.limit();
+        state = outcome.into_state()?;
+    }
+    ts.push(("eval", Instant::now()));
+
+    if flags.with_report {
+        let mut report = String::new();
+        report.push_str("State:\n");
+        report.push_str(&format!("  counter: {}\n", state.counter));
+        report.push_str(&format!("  pointer: {}\n", state.pointer));
+        let (left, right) = state.extents();
+        report.push_str(&format!("  memory: {} {}\n", left, right));
+        if flags.profile_allocations {
+            report.push_str(&format!("  tape growth events: {}\n", state.growth_events));
+        }
+        if flags.count_backtracks {
+            report.push_str(&format!("  loops entered: {}\n", state.loops_entered));
+            report.push_str(&format!("  loop back-edges: {}\n", state.back_edges));
+        }
+
+        let executions = state.executions.sorted();
+        let total: usize = executions.iter().map(|(_, n)| n).sum();
+        report.push_str("Executions:\n");
+        for (kind, n) in executions {
+            report.push_str(&format!(
+                "  {}: {} ({:.1}%)\n",
+                kind,
+                n,
+                n as f64 * 100.0 / total as f64
+            ));
+        }
+
+        report.push_str("Timings:\n");
+        for t in ts.windows(2) {
+            match flags.deterministic_time {
+                true => report.push_str(&format!("  {}: -\n", &t[1].0)),
+                false => report.push_str(&format!("  {}: {:.2?}\n", &t[1].0, &t[1].1.duration_since(t[0].1))),
+            }
+        }
+        write_report(flags, &report)?;
+    }
+    if flags.with_memdump {
+        eprintln!("Memory dump:");
+        let (left, right) = state.sides();
+        eprintln!(" left: {:?}", left);
+        eprintln!(" right: {:?}", right);
+    }
+    if let (Some(ProfileFormat::Folded), Some(profile)) = (flags.emit_profile, &state.profile) {
+        for line in profile.folded(filename) {
+            eprintln!("{}", line);
+        }
+    }
+    if flags.benchmark {
+        let eval_time = ts[ts.len() - 1].1.duration_since(ts[ts.len() - 2].1);
+        let per_second = |n: usize| n as f64 / eval_time.as_secs_f64().max(f64::EPSILON);
+        eprintln!("Benchmark:");
+        eprintln!("  instructions: {}", state.counter);
+        eprintln!("  eval: {:.2?}", eval_time);
+        eprintln!("  instructions/s: {:.0}", per_second(state.counter));
+        eprintln!("  bytes in: {} ({:.0}/s)", state.bytes_in, per_second(state.bytes_in));
+        eprintln!(
+            "  bytes out: {} ({:.0}/s)",
+            state.bytes_out,
+            per_second(state.bytes_out)
+        );
+    }
+    if let (Some(source), Some(hot)) = (&source, &state.hot) {
+        let mut offsets = HashMap::new();
+        node_offsets(&nodes, &positions, &mut offsets);
+        print!("{}", hot_positions_report(source, &offsets, hot));
+    }
+    if flags.emit == Some(Emit::MetricsPrometheus) {
+        metrics.push(RunMetrics {
+            program: filename.clone(),
+            instructions: state.counter,
+            cells: state.cells().count(),
+            bytes_in: state.bytes_in,
+            bytes_out: state.bytes_out,
+            eval: ts[ts.len() - 1].1.duration_since(ts[ts.len() - 2].1),
+        });
+    }
+    if flags.count_values {
+        let mut counts: std::collections::BTreeMap<Cell, usize> = std::collections::BTreeMap::new();
+        for c in state.cells() {
+            *counts.entry(c).or_default() += 1;
+        }
+        eprintln!("Cell values:");
+        for (value, count) in counts {
+            eprintln!("  {}: {}", value, count);
+        }
+    }
+    if let Some(limit) = limit {
+        return Err(limit_error(flags, filename, limit));
+    }
+    if let Some(expected) = flags.assert_final_cell {
+        let actual = state.cell_at(state.pointer);
+        ensure!(
+            actual == expected,
+            FinalCellMismatchSnafu {
+                filename: filename.clone(),
+                expected,
+                actual,
             }
+        );
+    }
+    return Ok(());
+}
+
+/// MetricFamily describes one exported metric: its name, type, help text and
+/// how to read its value from a run.
+type MetricFamily = (&'static str, &'static str, &'static str, fn(&RunMetrics) -> String);
+
+/// write_report sends a report to the file given with --report-file, or to
+/// stderr by default. A report file of '-' means stdout, where the report is
+/// framed so that it can be told apart from program output.
+fn write_report(flags: &Flags, report: &str) -> Result<()> {
+    match flags.report_file.as_deref() {
+        None => eprint!("{}", report),
+        Some("-") => {
+            let mut stdout = std::io::stdout();
+            write!(stdout, "\n--- bfstk report ---\n{}--- end bfstk report ---\n", report).context(WriteOutputSnafu)?;
+        }
+        Some(filename) => {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(filename)
+                .context(FileWriteSnafu { filename })?;
+            file.write_all(report.as_bytes()).context(FileWriteSnafu { filename })?;
         }
+    }
+    return Ok(());
+}
+
+/// RunMetrics holds the stats of one program run that are exported as
+/// Prometheus metrics.
+#[derive(Debug)]
+struct RunMetrics {
+    program: String,
+    instructions: usize,
+    cells: usize,
+    bytes_in: usize,
+    bytes_out: usize,
+    eval: std::time::Duration,
+}
 
-        Node::Read => {
-            state.counter += 1;
+impl RunMetrics {
+    /// exposition renders the metrics of every run in the Prometheus text
+    /// exposition format, with one sample per program in each metric family.
+    fn exposition(runs: &[RunMetrics]) -> String {
+        let families: [MetricFamily; 5] = [
+            ("bfstk_instructions_total", "counter", "Instructions executed.", |r| {
+                r.instructions.to_string()
+            }),
+            ("bfstk_tape_cells", "gauge", "Cells allocated on the tape.", |r| {
+                r.cells.to_string()
+            }),
+            (
+                "bfstk_input_bytes_total",
+                "counter",
+                "Bytes read by the program.",
+                |r| r.bytes_in.to_string(),
+            ),
+            (
+                "bfstk_output_bytes_total",
+                "counter",
+                "Bytes written by the program.",
+                |r| r.bytes_out.to_string(),
+            ),
+            (
+                "bfstk_eval_seconds",
+                "gauge",
+                "Time spent evaluating the program.",
+                |r| r.eval.as_secs_f64().to_string(),
+            ),
+        ];
 
-            let mut c = [0u8; 1];
-            std::io::stdin().read_exact(&mut c).context(ReadInputSnafu)?;
-            if state.pointer < 0 {
-                state.data_left[-state.pointer as usize] = c[0];
-            } else {
-                state.data_right[state.pointer as usize] = c[0];
+        let mut out = String::new();
+        for (name, kind, help, value) in families {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+            for run in runs {
+                let program = run
+                    .program
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                out.push_str(&format!("{}{{program=\"{}\"}} {}\n", name, program, value(run)));
             }
         }
-        Node::Write if state.pointer < 0 => {
-            state.counter += 1;
-            print!("{}", state.data_left[-state.pointer as usize] as char);
+        return out;
+    }
+}
+
+/// source_map lists, for every node of some generated code, the line of the
+/// code it starts on and the source line and column it came from, given where
+/// in the code each node starts and its source offset, both in order.
+fn source_map(code: &str, source: &str, map: &[(usize, usize)]) -> String {
+    let mut out = String::new();
+    let (mut line, mut at) = (1, 0);
+    let (mut src_line, mut src_col, mut offset) = (1, 1, 0);
+    for &(next_at, next_offset) in map {
+        line += code[at..next_at].matches('\n').count();
+        for ch in source[offset..next_offset].chars() {
+            match ch {
+                '\n' => (src_line, src_col) = (src_line + 1, 1),
+                _ => src_col += 1,
+            }
         }
-        Node::Write => {
-            state.counter += 1;
-            print!("{}", state.data_right[state.pointer as usize] as char);
+        (at, offset) = (next_at, next_offset);
+        out.push_str(&format!("{} {}:{}\n", line, src_line, src_col));
+    }
+    return out;
+}
+
+/// emitted_tape returns how many cells the tape of an emitted program holds
+/// and the index of cell 0, following the tape flags. Without them the tape
+/// holds every cell an i16 pointer can reach, with cell 0 in the middle.
+fn emitted_tape(flags: &Flags) -> (usize, usize) {
+    let sized = flags
+        .wrap_tape
+        .or(flags.fixed_tape.map(|size| size.max(1)))
+        .or(flags.tape_size);
+    return match (sized, flags.no_negative) {
+        (Some(size), _) => (size, 0),
+        (None, true) => (i16::MAX as usize + 1, 0),
+        (None, false) => (1 << 16, 1 << 15),
+    };
+}
+
+/// emit_c transpiles nodes to C statements that behave like running them with
+/// the given flags' cell width, loop polarity, EOF handling and tape wrapping,
+/// starting with p pointing at cell 0 of a tape named tape. With
+/// --with-runtime they are wrapped in a complete program that declares the
+/// tape, sized as the tape flags ask, and compiles as is.
+fn emit_c(nodes: &[Node], flags: &Flags, positions: &[Position], map: &mut Vec<(usize, usize)>) -> String {
+    let cell = match flags.cell_bits {
+        CellBits::Eight => "uint8_t",
+        CellBits::Sixteen => "uint16_t",
+        CellBits::ThirtyTwo => "uint32_t",
+    };
+    let (cells, origin) = emitted_tape(flags);
+    let mut out = String::new();
+    if !flags.with_runtime {
+        out.push_str(&format!(
+            "// generated by bfstk --emit=c, with p pointing at cell 0 of {} tape[{}]\n",
+            cell, cells
+        ));
+        emit_c_nodes(nodes, flags, 0, &mut out, positions, map);
+        return out;
+    }
+    out.push_str("#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n\n");
+    out.push_str(&format!("static {} tape[{}];\n\n", cell, cells));
+    out.push_str("int main(void) {\n");
+    match origin {
+        0 => out.push_str(&format!("    {} *p = tape;\n", cell)),
+        origin => out.push_str(&format!("    {} *p = tape + {};\n", cell, origin)),
+    }
+    emit_c_nodes(nodes, flags, 1, &mut out, positions, map);
+    out.push_str("    return 0;\n}\n");
+    return out;
+}
+
+/// emit_c_nodes writes the C statements for nodes at the given depth of
+/// nesting, one per line. When the nodes come with their positions, map gets
+/// where in out each node starts, along with its source offset.
+fn emit_c_nodes(
+    nodes: &[Node],
+    flags: &Flags,
+    depth: usize,
+    out: &mut String,
+    positions: &[Position],
+    map: &mut Vec<(usize, usize)>,
+) {
+    let indent = "    ".repeat(depth);
+    let signed = |op: &str, n: i64| match n < 0 {
+        true => format!("{}-= {}", op, -n),
+        false => format!("{}+= {}", op, n),
+    };
+    // on a wrapping tape, moves and offsets go around the end of the tape
+    let step = |by: i64| match flags.wrap_tape {
+        Some(n) => format!("p = tape + (p - tape + {}) % {}", by.rem_euclid(n as i64), n),
+        None => signed("p ", by),
+    };
+    let at = |offset: i16| match flags.wrap_tape {
+        Some(n) => format!("tape[(p - tape + {}) % {}]", i64::from(offset).rem_euclid(n as i64), n),
+        None => format!("p[{}]", offset),
+    };
+    for (i, node) in nodes.iter().enumerate() {
+        if let (Some(position), false) = (positions.get(i), matches!(node, Node::Comment(_))) {
+            map.push((out.len(), position.offset));
         }
+        let line = match node {
+            Node::Comment(_) => continue,
+            Node::Delta(i) => format!("{};", signed("*p ", (*i).into())),
+            Node::Move(i) => format!("{};", step((*i).into())),
+            Node::Read => {
+                let eof = match flags.eof {
+                    Eof::Unchanged => "",
+                    Eof::Zero => " else *p = 0;",
+                    Eof::Max => " else *p = -1;",
+                    Eof::Error => " else { fputs(\"unexpected end of input\\n\", stderr); exit(2); }",
+                };
+                format!("{{ int c = getchar(); if (c != EOF) *p = c;{} }}", eof)
+            }
+            Node::Write => "putchar(*p);".to_string(),
+            Node::Flush => "fflush(stdout);".to_string(),
+            Node::SetZero => "*p = 0;".to_string(),
+            Node::ClearRange(from, to) => format!("memset(p + ({}), 0, {} * sizeof *p);", from, to - from + 1),
+            Node::Scan(by) => format!("while (*p) {};", step((*by).into())),
+            Node::OffsetDeltas(terms, net) => {
+                for (offset, delta) in terms {
+                    out.push_str(&format!(
+                        "{}{};\n",
+                        indent,
+                        signed(&format!("{} ", at(*offset)), (*delta).into())
+                    ));
+                }
+                match *net {
+                    0 => continue,
+                    net => format!("{};", step(net.into())),
+                }
+            }
+            Node::MulAdd(terms) => {
+                for (offset, factor) in terms {
+                    let term = signed(&format!("{} ", at(*offset)), (*factor).into());
+                    out.push_str(&format!("{}{};\n", indent, term.replacen("= ", "= *p * ", 1)));
+                }
+                "*p = 0;".to_string()
+            }
+            Node::Block(_, body) => {
+                let condition = match flags.loop_polarity {
+                    LoopPolarity::NonZero => "*p",
+                    LoopPolarity::Zero => "!*p",
+                };
+                out.push_str(&format!("{}while ({}) {{\n", indent, condition));
+                let body_positions = positions.get(i).map_or(&[][..], |position| &position.body);
+                emit_c_nodes(body, flags, depth + 1, out, body_positions, map);
+                "}".to_string()
+            }
+        };
+        out.push_str(&format!("{}{}\n", indent, line));
+    }
+}
+
+/// emit_rust transpiles nodes to a standalone Rust program that behaves like
+/// running them with the given flags' cell width, loop polarity, EOF handling
+/// and tape wrapping. Like emit_c, the tape is sized as the tape flags ask.
+fn emit_rust(nodes: &[Node], flags: &Flags, positions: &[Position], map: &mut Vec<(usize, usize)>) -> String {
+    let cell = match flags.cell_bits {
+        CellBits::Eight => "u8",
+        CellBits::Sixteen => "u16",
+        CellBits::ThirtyTwo => "u32",
+    };
+    let mut out = String::new();
+    out.push_str("// generated by bfstk --emit=rust\n");
+    out.push_str("#![allow(unused_imports, unused_mut)]\n\n");
+    out.push_str("use std::io::{Read, Write};\n\n");
+    out.push_str("fn main() {\n");
+    let (cells, origin) = emitted_tape(flags);
+    out.push_str(&format!("    let mut tape: Vec<{}> = vec![0; {}];\n", cell, cells));
+    out.push_str(&format!("    let mut p: usize = {};\n", origin));
+    out.push_str("    let mut out = std::io::BufWriter::new(std::io::stdout().lock());\n");
+    emit_rust_nodes(nodes, flags, 1, &mut out, positions, map);
+    out.push_str("    out.flush().unwrap();\n}\n");
+    return out;
+}
 
-        Node::Block(subprogram) => {
-            state.counter += 1;
-            while state.data_right[state.pointer as usize] != 0 {
-                for node in subprogram.clone() {
-                    state = eval(state, node)?;
+/// emit_rust_nodes writes the Rust statements for nodes at the given depth of
+/// nesting, one per line. When the nodes come with their positions, map gets
+/// where in out each node starts, along with its source offset.
+fn emit_rust_nodes(
+    nodes: &[Node],
+    flags: &Flags,
+    depth: usize,
+    out: &mut String,
+    positions: &[Position],
+    map: &mut Vec<(usize, usize)>,
+) {
+    let indent = "    ".repeat(depth);
+    let modulus = i64::from(flags.cell_bits.max()) + 1;
+    // on a wrapping tape, moves and offsets go around the end of the tape
+    let index = |offset: i16| match (offset, flags.wrap_tape) {
+        (0, _) => "p".to_string(),
+        (o, Some(n)) => format!("(p + {}) % {}", i64::from(o).rem_euclid(n as i64), n),
+        (o, None) if o < 0 => format!("p - {}", -o),
+        (o, None) => format!("p + {}", o),
+    };
+    let step = |by: i16| match (by, flags.wrap_tape) {
+        (by, Some(n)) => format!("p = (p + {}) % {};", i64::from(by).rem_euclid(n as i64), n),
+        (by, None) if by < 0 => format!("p -= {};", -by),
+        (by, None) => format!("p += {};", by),
+    };
+    let cell = |offset: i16| format!("tape[{}]", index(offset));
+    for (i, node) in nodes.iter().enumerate() {
+        if let (Some(position), false) = (positions.get(i), matches!(node, Node::Comment(_))) {
+            map.push((out.len(), position.offset));
+        }
+        let line = match node {
+            Node::Comment(_) => continue,
+            Node::Delta(i) => format!("tape[p] = tape[p].wrapping_add({});", i64::from(*i).rem_euclid(modulus)),
+            Node::Move(i) => step(*i),
+            Node::Read => {
+                let eof = match flags.eof {
+                    Eof::Unchanged => "None => {}".to_string(),
+                    Eof::Zero => "None => tape[p] = 0,".to_string(),
+                    Eof::Max => "None => tape[p] = !0,".to_string(),
+                    Eof::Error => {
+                        "None => {\n".to_string()
+                            + &format!("{}        eprintln!(\"unexpected end of input\");\n", indent)
+                            + &format!("{}        std::process::exit(2);\n", indent)
+                            + &format!("{}    }}", indent)
+                    }
+                };
+                out.push_str(&format!("{}out.flush().unwrap();\n", indent));
+                out.push_str(&format!("{}match std::io::stdin().lock().bytes().next() {{\n", indent));
+                out.push_str(&format!("{}    Some(c) => tape[p] = c.unwrap().into(),\n", indent));
+                out.push_str(&format!("{}    {}\n", indent, eof));
+                "}".to_string()
+            }
+            Node::Write => "out.write_all(&[tape[p] as u8]).unwrap();".to_string(),
+            Node::Flush => "out.flush().unwrap();".to_string(),
+            Node::SetZero => "tape[p] = 0;".to_string(),
+            Node::ClearRange(from, to) => format!("tape[{}..={}].fill(0);", index(*from), index(*to)),
+            Node::Scan(by) => format!("while tape[p] != 0 {{ {} }}", step(*by)),
+            Node::OffsetDeltas(terms, net) => {
+                for (offset, delta) in terms {
+                    out.push_str(&format!(
+                        "{}{} = {}.wrapping_add({});\n",
+                        indent,
+                        cell(*offset),
+                        cell(*offset),
+                        i64::from(*delta).rem_euclid(modulus)
+                    ));
+                }
+                match *net {
+                    0 => continue,
+                    net => step(net),
                 }
             }
+            Node::MulAdd(terms) => {
+                for (offset, factor) in terms {
+                    out.push_str(&format!(
+                        "{}{} = {}.wrapping_add(tape[p].wrapping_mul({}));\n",
+                        indent,
+                        cell(*offset),
+                        cell(*offset),
+                        i64::from(*factor).rem_euclid(modulus)
+                    ));
+                }
+                "tape[p] = 0;".to_string()
+            }
+            Node::Block(_, body) => {
+                let condition = match flags.loop_polarity {
+                    LoopPolarity::NonZero => "!=",
+                    LoopPolarity::Zero => "==",
+                };
+                out.push_str(&format!("{}while tape[p] {} 0 {{\n", indent, condition));
+                let body_positions = positions.get(i).map_or(&[][..], |position| &position.body);
+                emit_rust_nodes(body, flags, depth + 1, out, body_positions, map);
+                "}".to_string()
+            }
+        };
+        out.push_str(&format!("{}{}\n", indent, line));
+    }
+}
+
+/// tokens_json serializes tokens as a JSON array, on a single line unless
+/// pretty asks for one field per line, indented.
+fn tokens_json(tokens: &[Token], pretty: bool) -> String {
+    let objects: Vec<String> = tokens
+        .iter()
+        .map(|t| {
+            let mut fields = vec![
+                ("kind", json_string(t.kind.name())),
+                ("offset", t.offset.to_string()),
+                ("line", t.line.to_string()),
+                ("col", t.col.to_string()),
+            ];
+            if let TokenKind::Comment(c) = t.kind {
+                fields.push(("char", json_string(&c.to_string())));
+            }
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(name, value)| match pretty {
+                    true => format!("    \"{}\": {}", name, value),
+                    false => format!("\"{}\":{}", name, value),
+                })
+                .collect();
+            return match pretty {
+                true => format!("  {{\n{}\n  }}", fields.join(",\n")),
+                false => format!("{{{}}}", fields.join(",")),
+            };
+        })
+        .collect();
+    return match (pretty, objects.is_empty()) {
+        (true, false) => format!("[\n{}\n]", objects.join(",\n")),
+        _ => format!("[{}]", objects.join(",")),
+    };
+}
+
+/// json_string quotes and escapes a string for use as a JSON value.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
     }
+    out.push('"');
+    return out;
+}
+
+/// extend_tokens turns the comment characters of any enabled extension
+/// instructions into their tokens.
+fn extend_tokens(flags: &Flags, mut tokens: Vec<Token>) -> Vec<Token> {
+    if flags.flush_extension {
+        for token in tokens.iter_mut().filter(|t| matches!(t.kind, TokenKind::Comment('&'))) {
+            token.kind = TokenKind::Flush;
+        }
+    }
+    return tokens;
+}
 
-    return Ok(state);
+/// check_matching reports the first unmatched '[' and the first unmatched ']'
+/// in the source together, by line and column, if any bracket is unmatched.
+fn check_matching(filename: &str, source: &str) -> Result<()> {
+    let mut opens: Vec<usize> = vec![];
+    let mut closer = None;
+    for (offset, ch) in source.char_indices() {
+        match ch {
+            '[' => opens.push(offset),
+            ']' if opens.pop().is_none() && closer.is_none() => closer = Some(offset),
+            _ => {}
+        }
+    }
+
+    let position = |offset: usize| {
+        let (line, col) = line_col(source, offset);
+        return format!("{}:{}", line, col);
+    };
+    let mut unmatched = vec![];
+    if let Some(&offset) = opens.first() {
+        unmatched.push(format!("'[' at {}", position(offset)));
+    }
+    if let Some(offset) = closer {
+        unmatched.push(format!("']' at {}", position(offset)));
+    }
+    ensure!(
+        unmatched.is_empty(),
+        UnmatchedBracketsSnafu {
+            filename,
+            unmatched: unmatched.join(" and "),
+        }
+    );
+    return Ok(());
+}
+
+/// optimize rewrites a parsed program into nodes that run faster but behave
+/// the same under the given flags.
+fn optimize(flags: &Flags, nodes: Vec<Node>) -> Vec<Node> {
+    return run_passes(nodes, &optimizer_passes(flags));
+}
+
+/// opt_report runs the optimizer one pass at a time and lists how many nodes,
+/// counting those inside loops, the program has before and after each pass.
+fn opt_report(flags: &Flags, nodes: Vec<Node>) -> String {
+    fn count(nodes: &[Node]) -> usize {
+        return nodes
+            .iter()
+            .map(|node| match node {
+                Node::Block(_, body) => 1 + count(body),
+                _ => 1,
+            })
+            .sum();
+    }
+
+    let mut out = format!("{:<16}{:>8}{:>8}\n", "pass", "before", "after");
+    let mut nodes = nodes;
+    for pass in optimizer_passes(flags) {
+        let before = count(&nodes);
+        nodes = (pass.run)(nodes);
+        out.push_str(&format!("{:<16}{:>8}{:>8}\n", pass.name, before, count(&nodes)));
+    }
+    return out;
+}
+
+/// optimizer_passes returns the passes of the chosen -O level that keep the
+/// program behaving the same under the given flags.
+fn optimizer_passes(flags: &Flags) -> Vec<Pass> {
+    if flags.counts_steps() {
+        return vec![];
+    }
+    let allowed = |name: &str| match name {
+        // a loop limit has to see every iteration
+        "scan-loops" => flags.loop_polarity == LoopPolarity::NonZero && flags.max_loop_iterations.is_none(),
+        // a clear loop only ends at zero when cells wrap and loops exit on zero
+        "clear-loops" | "mul-loops" => {
+            flags.overflow == Overflow::Wrap
+                && flags.loop_polarity == LoopPolarity::NonZero
+                && flags.max_loop_iterations.is_none()
+        }
+        // deltas folded together can overflow differently from one at a time,
+        // and out of bounds errors find their source position by node, which
+        // needs the nodes to still line up with the source
+        "offset-deltas" => flags.overflow == Overflow::Wrap && !flags.bounded_tape(),
+        // a range of clears skips over the cells between its ends, which only
+        // works on a tape that grows without wrapping around
+        "clear-ranges" => {
+            flags.overflow == Overflow::Wrap
+                && flags.loop_polarity == LoopPolarity::NonZero
+                && flags.max_loop_iterations.is_none()
+                && !flags.bounded_tape()
+                && flags.wrap_tape.is_none()
+        }
+        _ => true,
+    };
+    let level = OPT_LEVELS[flags.opt_level];
+    return passes()
+        .into_iter()
+        .filter(|pass| level.contains(&pass.name) && allowed(pass.name))
+        .collect();
 }
@@ -0,0 +1,881 @@
+#![allow(clippy::needless_return)]
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+
+/// PRINT_A leaves 65, 'A', in the current cell, using the cell to its right.
+const PRINT_A: &str = "++++++++[>++++++++<-]>+.";
+
+/// bfstk runs the binary with the given arguments, feeding it stdin, and
+/// waits for it to finish.
+fn bfstk(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = spawn(args);
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    return child.wait_with_output().unwrap();
+}
+
+/// spawn starts the binary with piped stdin, stdout and stderr.
+fn spawn(args: &[&str]) -> Child {
+    return Command::new(env!("CARGO_BIN_EXE_bfstk"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+}
+
+fn stdout(output: &Output) -> String {
+    return String::from_utf8_lossy(&output.stdout).to_string();
+}
+
+fn stderr(output: &Output) -> String {
+    return String::from_utf8_lossy(&output.stderr).to_string();
+}
+
+/// scratch returns an empty directory for a test to write files into.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bfstk-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+/// file writes contents to a file in dir, returning its path as a string.
+fn file(dir: &std::path::Path, name: &str, contents: &str) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    return path.to_string_lossy().to_string();
+}
+
+/// Json is a parsed JSON value, for checking the machine-readable output of
+/// bfstk.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// get returns the value of a field, if this is an object that has it.
+    fn get(&self, field: &str) -> Option<&Json> {
+        let Json::Object(fields) = self else {
+            return None;
+        };
+        return fields.iter().find(|(name, _)| name == field).map(|(_, value)| value);
+    }
+
+    fn number(&self) -> Option<f64> {
+        return match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        };
+    }
+
+    fn str(&self) -> Option<&str> {
+        return match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        };
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+/// parse_json parses a whole JSON document, panicking if it isn't valid.
+fn parse_json(s: &str) -> Json {
+    let mut chars = s.chars().peekable();
+    let value = json_value(&mut chars);
+    skip_whitespace(&mut chars);
+    assert_eq!(chars.next(), None, "trailing characters in {:?}", s);
+    return value;
+}
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn json_value(chars: &mut Chars) -> Json {
+    skip_whitespace(chars);
+    let literal = |chars: &mut Chars, rest: &str, value: Json| {
+        for expected in rest.chars() {
+            assert_eq!(chars.next(), Some(expected));
+        }
+        return value;
+    };
+    match chars.next() {
+        Some('n') => return literal(chars, "ull", Json::Null),
+        Some('t') => return literal(chars, "rue", Json::Bool(true)),
+        Some('f') => return literal(chars, "alse", Json::Bool(false)),
+        Some('"') => return Json::String(json_string(chars)),
+        Some('[') => {
+            let mut items = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Json::Array(items);
+            }
+            loop {
+                items.push(json_value(chars));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Json::Array(items),
+                    c => panic!("expected ',' or ']' in array, found {:?}", c),
+                }
+            }
+        }
+        Some('{') => {
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Json::Object(fields);
+            }
+            loop {
+                skip_whitespace(chars);
+                assert_eq!(chars.next(), Some('"'), "expected a field name");
+                let name = json_string(chars);
+                skip_whitespace(chars);
+                assert_eq!(chars.next(), Some(':'), "expected ':' after field {:?}", name);
+                fields.push((name, json_value(chars)));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Json::Object(fields),
+                    c => panic!("expected ',' or '}}' in object, found {:?}", c),
+                }
+            }
+        }
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = c.to_string();
+            while let Some(d) = chars.next_if(|d| d.is_ascii_digit() || "+-.eE".contains(*d)) {
+                number.push(d);
+            }
+            return Json::Number(number.parse().unwrap());
+        }
+        c => panic!("expected a JSON value, found {:?}", c),
+    }
+}
+
+/// json_string reads the rest of a string whose opening quote has been read.
+fn json_string(chars: &mut Chars) -> String {
+    let mut s = String::new();
+    loop {
+        match chars.next().expect("unterminated string") {
+            '"' => return s,
+            '\\' => match chars.next().expect("unterminated escape") {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    s.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+#[test]
+fn folded_profile_lists_nested_loop_paths() {
+    let output = bfstk(&["--emit-profile=folded", "-e", "++[>++[-]<-]"], b"");
+    assert_eq!(stderr(&output), "-e;loop@2 2\n-e;loop@2;loop@6 4\n");
+}
+
+#[test]
+fn echo_program_comes_before_output() {
+    let output = bfstk(&["--echo-program", "-e", PRINT_A], b"");
+    assert!(stderr(&output).contains(PRINT_A));
+    assert_eq!(output.stdout, b"A");
+}
+
+#[test]
+fn max_tape_bytes_counts_wide_cells() {
+    // two 32-bit cells fit in 8 bytes, but three don't
+    let output = bfstk(&["--cell-bits", "32", "--max-tape-bytes", "8", "-e", ">"], b"");
+    assert!(output.status.success());
+    let output = bfstk(&["--cell-bits", "32", "--max-tape-bytes", "8", "-e", ">>"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("needs 12 bytes of tape, over the limit of 8"));
+}
+
+#[test]
+fn sample_every_fires_per_n_instructions() {
+    let output = bfstk(&["--sample-every", "2", "-e", "+>+>+>"], b"");
+    assert_eq!(stderr(&output).matches("sample:").count(), 3);
+}
+
+#[test]
+fn run_until_tape_halts_on_the_pattern() {
+    let program = "++++++++[>++++++++<-]>+[<+>-]++++++++[>++++++++<-]>++[<+>-]<<[-]+++";
+    let output = bfstk(&["--run-until-tape", "4142", "-d", "-e", program], b"");
+    // it halts on the add that completes the pattern, before the loop has
+    // finished emptying cell 2
+    assert!(stderr(&output).contains("right: [65, 66, 1]"), "{}", stderr(&output));
+}
+
+#[test]
+fn deterministic_time_makes_reports_repeatable() {
+    let args = ["-r", "--deterministic-time", "-e", "+++[>+<-]"];
+    let first = bfstk(&args, b"");
+    assert!(stderr(&first).contains("eval: -"));
+    assert_eq!(stderr(&first), stderr(&bfstk(&args, b"")));
+}
+
+#[test]
+fn json_trace_lines_parse_as_json() {
+    let output = bfstk(&["--trace", "--trace-format=json", "-e", "+>"], b"");
+    let steps: Vec<Json> = stderr(&output).lines().map(parse_json).collect();
+    assert_eq!(steps.len(), 2);
+    for (i, step) in steps.iter().enumerate() {
+        assert_eq!(step.get("step"), Some(&Json::Number(i as f64 + 1.0)));
+        assert!(step.get("node").and_then(Json::str).is_some(), "{:?}", step);
+        for field in ["pointer", "before", "after"] {
+            assert!(
+                step.get(field).and_then(Json::number).is_some(),
+                "{} in {:?}",
+                field,
+                step
+            );
+        }
+    }
+    assert_eq!(steps[0].get("node"), Some(&Json::String("Delta(1)".to_string())));
+    assert_eq!(steps[0].get("after"), Some(&Json::Number(1.0)));
+    assert_eq!(steps[1].get("node"), Some(&Json::String("Move(1)".to_string())));
+}
+
+#[test]
+fn preload_sets_up_the_tape() {
+    let dir = scratch("preload");
+    let prologue = file(&dir, "prologue.b", &"+".repeat(65));
+    let output = bfstk(&["--preload", &prologue, "-e", "."], b"");
+    assert_eq!(output.stdout, b"A");
+}
+
+#[test]
+fn explain_parse_logs_each_decision() {
+    let output = bfstk(&["--explain-parse", "-e", "++>."], b"");
+    let log = stderr(&output);
+    assert!(log.contains("offset 1: merged '+' into Delta(2)"));
+    assert!(log.contains("offset 2: started new Move(1) node because previous was Delta(2)"));
+    assert!(log.contains("offset 3: started new Write node, which is never merged"));
+}
+
+#[test]
+fn summary_counts_loops_before_optimizing() {
+    let output = bfstk(&["--emit=summary", "-e", "+[-]>[>]<[->+<]"], b"");
+    assert!(stdout(&output).contains("loops: 3\n"));
+    assert!(stdout(&output).contains("max depth: 1\n"));
+}
+
+#[test]
+fn opt_report_counts_nodes_around_each_pass() {
+    let output = bfstk(&["--emit=opt-report", "-e", "+++[-]>+"], b"");
+    assert_eq!(output.status.code(), Some(0));
+    let out = stdout(&output);
+    assert!(out.starts_with("pass              before   after\n"), "{}", out);
+    assert!(out.contains("\ncoalesce               5       5\n"), "{}", out);
+    assert!(out.contains("\nclear-loops            5       4\n"), "{}", out);
+
+    // -O0 runs no passes, so there is nothing to report
+    let output = bfstk(&["--emit=opt-report", "-O0", "-e", "+++[-]>+"], b"");
+    assert_eq!(stdout(&output).lines().count(), 1);
+}
+
+#[test]
+fn loop_polarity_zero_runs_loops_on_zero_cells() {
+    let output = bfstk(&["--loop-polarity", "zero", "-d", "-e", "[+]"], b"");
+    assert!(stderr(&output).contains("right: [1]"));
+    let output = bfstk(&["-d", "-e", "[+]"], b"");
+    assert!(stderr(&output).contains("right: [0]"));
+}
+
+#[test]
+fn input_repeat_cycles_forever() {
+    let output = bfstk(&["--input-repeat", "AB", "--max-steps", "100", "-e", ",[.,]"], b"");
+    assert!(stderr(&output).contains("limit of 100 steps"));
+    let out = stdout(&output);
+    assert!(out.len() > 10);
+    assert!(out.starts_with("ABABAB"));
+    assert!(!out.contains("AA") && !out.contains("BB"));
+}
+
+#[test]
+fn canonical_newlines_write_crlf() {
+    let output = bfstk(&["--canonical-newlines=crlf", "-e", ",.,.,."], b"A\nB");
+    assert_eq!(output.stdout, b"A\r\nB");
+}
+
+#[test]
+fn frames_decode_back_to_the_output() {
+    let output = bfstk(&["--output-protocol=frames", "-e", ",.,.,.,."], b"ab\ncd");
+    let mut bytes = &output.stdout[..];
+    let mut decoded = vec![];
+    while !bytes.is_empty() {
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        decoded.extend_from_slice(&bytes[4..4 + len]);
+        bytes = &bytes[4 + len..];
+    }
+    assert_eq!(decoded, b"ab\ncd"[..4]);
+}
+
+#[test]
+fn watch_runs_again_when_the_file_changes() {
+    let dir = scratch("watch");
+    let program = file(&dir, "prog.b", PRINT_A);
+    let mut child = spawn(&["--watch", "--watch-interval", "5", &program]);
+    let mut stdout = child.stdout.take().unwrap();
+
+    // each read waits for a run to finish, so there's no need to sleep
+    let mut first = [0; 1];
+    stdout.read_exact(&mut first).unwrap();
+    std::fs::write(&program, format!("{}+.", PRINT_A)).unwrap();
+    let mut second = [0; 2];
+    stdout.read_exact(&mut second).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    // stdout is a pipe rather than a terminal, so nothing clears it
+    assert_eq!(&first, b"A");
+    assert_eq!(&second, b"AB");
+}
+
+#[test]
+fn read_grows_the_tape_to_the_left() {
+    let output = bfstk(&["-e", "<,."], b"x");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"x");
+}
+
+#[test]
+fn assert_final_cell_checks_the_current_cell() {
+    let program = "+".repeat(42);
+    assert!(bfstk(&["--assert-final-cell", "42", "-e", &program], b"")
+        .status
+        .success());
+
+    let output = bfstk(&["--assert-final-cell", "41", "-e", &program], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("holds 42, expected 41"));
+}
+
+#[test]
+fn report_shows_the_final_pointer() {
+    let output = bfstk(&["-r", "-e", ">>>"], b"");
+    assert!(stderr(&output).contains("pointer: 3\n"));
+}
+
+#[test]
+fn profile_allocations_counts_growth() {
+    let program = ">+".repeat(100);
+    let growing = bfstk(&["-r", "--profile-allocations", "-e", &program], b"");
+    let fixed = bfstk(
+        &["-r", "--profile-allocations", "--fixed-tape", "101", "-e", &program],
+        b"",
+    );
+    assert!(!stderr(&growing).contains("tape growth events: 0\n"));
+    assert!(stderr(&fixed).contains("tape growth events: 0\n"));
+}
+
+#[test]
+fn fixed_tape_runs_programs_that_fit() {
+    let output = bfstk(&["--fixed-tape", "3", "-d", "-e", ">>+"], b"");
+    assert!(stderr(&output).contains("right: [0, 0, 1]"));
+
+    let output = bfstk(&["--fixed-tape", "3", "-e", ">>>"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("cell 3"));
+    assert!(stderr(&output).contains("cells 0 to 2"));
+}
+
+#[test]
+fn max_loop_iterations_stops_a_runaway_loop() {
+    let output = bfstk(&["--max-loop-iterations", "100", "-e", "+[]"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("exceeded the limit of 100 iterations"));
+}
+
+#[test]
+fn tokens_json_deserializes_to_tokens() {
+    let output = bfstk(&["--emit=tokens-json", "-e", "+[\n.]x"], b"");
+    let Json::Array(tokens) = parse_json(&stdout(&output)) else {
+        panic!("expected an array of tokens");
+    };
+    let kinds: Vec<&str> = tokens
+        .iter()
+        .map(|t| t.get("kind").and_then(Json::str).unwrap())
+        .collect();
+    assert_eq!(
+        kinds,
+        ["IncrementByte", "JumpRight", "Comment", "Output", "JumpLeft", "Comment"]
+    );
+
+    // every position is a whole number, and line and col count from 1
+    let at = |t: &Json| ["offset", "line", "col"].map(|f| t.get(f).and_then(Json::number).unwrap());
+    for token in &tokens {
+        let [offset, line, col] = at(token);
+        assert!(
+            offset.fract() == 0.0 && line.fract() == 0.0 && col.fract() == 0.0,
+            "{:?}",
+            token
+        );
+        assert!(offset >= 0.0 && line >= 1.0 && col >= 1.0, "{:?}", token);
+    }
+    assert_eq!(at(&tokens[3]), [3.0, 2.0, 1.0]);
+    assert_eq!(at(&tokens[5]), [5.0, 2.0, 3.0]);
+    assert_eq!(tokens[5].get("char"), Some(&Json::String("x".to_string())));
+    assert_eq!(tokens[2].get("char"), Some(&Json::String("\n".to_string())));
+    assert_eq!(tokens[0].get("char"), None);
+}
+
+#[test]
+fn count_distinct_values_histograms_the_tape() {
+    let output = bfstk(&["--count-distinct-values", "-e", "+>++>++>"], b"");
+    assert!(
+        stderr(&output).contains("  0: 1\n  1: 1\n  2: 2\n"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn benchmark_reports_throughput() {
+    let output = bfstk(&["--benchmark", "-e", "+++.>+"], b"");
+    let report = stderr(&output);
+    assert!(report.contains("instructions: 3\n"), "{}", report);
+    let rate = report
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("instructions/s: "))
+        .unwrap();
+    assert!(rate.parse::<f64>().unwrap() > 0.0);
+}
+
+#[test]
+fn input_file_list_gives_each_program_its_input() {
+    let dir = scratch("input-file-list");
+    let a = file(&dir, "a.b", ",.");
+    let b = file(&dir, "b.b", ",+.");
+    let a_in = file(&dir, "a.in", "x");
+    let b_in = file(&dir, "b.in", "x");
+    let list = file(&dir, "list", &format!("{} {}\n{} {}\n", a, a_in, b, b_in));
+    let output = bfstk(&["--input-file-list", &list, &a, &b], b"");
+    assert_eq!(output.stdout, b"xy");
+}
+
+#[test]
+fn trace_cells_log_each_change() {
+    let output = bfstk(&["--trace-cells", "0", "-e", "+.+.+."], b"");
+    assert_eq!(stderr(&output).matches("watch: cell 0 changed").count(), 3);
+    assert!(stderr(&output).contains("changed 2->3"));
+}
+
+#[test]
+fn verify_deterministic_passes_deterministic_programs() {
+    let output = bfstk(&["--verify-deterministic", "-e", ",[.,]"], b"hello\0");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(output.stdout, b"hello");
+
+    // the tape always starts out zeroed, so reading it first is still fine
+    let output = bfstk(&["--verify-deterministic", "-e", ">>.<<+."], b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn verify_deterministic_warns_once() {
+    let output = bfstk(&["--verify-deterministic", "--warn-below", "0", "-e", "<+>"], b"");
+    assert_eq!(stderr(&output).matches("warning:").count(), 1);
+}
+
+#[test]
+fn prometheus_metrics_have_types_and_values() {
+    let output = bfstk(&["--emit=metrics-prometheus", "-e", "+++."], b"");
+    let metrics = stdout(&output);
+    assert!(metrics.contains("# TYPE bfstk_instructions_total counter\n"));
+    assert!(metrics.contains("bfstk_instructions_total{program=\"-e\"} 2\n"));
+    assert!(metrics.contains("bfstk_output_bytes_total{program=\"-e\"} 1\n"));
+}
+
+#[test]
+fn overflow_saturate_clamps_at_zero() {
+    let output = bfstk(&["--overflow=saturate", "-d", "-e", "-"], b"");
+    assert!(stderr(&output).contains("right: [0]"));
+    let output = bfstk(&["--overflow=wrap", "-d", "-e", "-"], b"");
+    assert!(stderr(&output).contains("right: [255]"));
+}
+
+#[test]
+fn lex_only_counts_tokens_without_running() {
+    let dir = scratch("lex-only");
+    let program = file(&dir, "prog.b", PRINT_A);
+    let output = bfstk(&["--lex-only", &program], b"");
+    assert!(output.stdout.is_empty());
+    assert!(stderr(&output).contains(&format!("tokens: {}\n", PRINT_A.len())));
+}
+
+#[test]
+fn loop_condition_reads_cells_left_of_zero() {
+    let output = bfstk(&["-d", "-e", "<+++[->>+<<]"], b"");
+    assert!(stderr(&output).contains("left: [0]"), "{}", stderr(&output));
+    assert!(stderr(&output).contains("right: [0, 3]"), "{}", stderr(&output));
+}
+
+#[test]
+fn report_file_takes_the_report() {
+    let dir = scratch("report-file");
+    let report = dir.join("report.txt");
+    let output = bfstk(&["-r", "--report-file", report.to_str().unwrap(), "-e", "+"], b"");
+    assert!(!stderr(&output).contains("counter:"));
+    assert!(std::fs::read_to_string(report).unwrap().contains("counter: 1"));
+}
+
+#[test]
+fn strict_matching_names_the_dangling_bracket() {
+    let output = bfstk(&["--strict-matching", "-e", "[]["], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("'[' at 1:3"));
+}
+
+#[test]
+fn eof_modes_decide_what_reads_leave() {
+    for (eof, value) in [("unchanged", "7"), ("0", "0"), ("255", "255")] {
+        let output = bfstk(&["--eof", eof, "-d", "-e", "+++++++,"], b"");
+        assert!(stderr(&output).contains(&format!("right: [{}]", value)), "{}", eof);
+    }
+    let output = bfstk(&["--eof", "error", "-e", ","], b"");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn arg_cells_seed_the_tape() {
+    let output = bfstk(&["--arg-cells", "65", "66", "-e", ".>."], b"");
+    assert_eq!(output.stdout, b"AB");
+}
+
+#[test]
+fn deeply_nested_loops_run() {
+    let program = format!("+{}-{}", "[".repeat(5000), "]".repeat(5000));
+    let output = bfstk(&["-d", "-e", &program], b"");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("right: [0]"));
+}
+
+#[test]
+fn program_is_read_from_stdin() {
+    let output = bfstk(&[], PRINT_A.as_bytes());
+    assert_eq!(output.stdout, b"A");
+
+    let output = bfstk(&["--eof=0", "-"], b",[.,]!hello");
+    assert_eq!(output.stdout, b"hello");
+}
+
+#[test]
+fn progress_bar_logs_a_line_per_program() {
+    let output = bfstk(&["--progress-bar", "-e", "+", "-e", "+"], b"");
+    assert!(stderr(&output).contains("progress: 1/1 files, 1 passed, 0 failed"));
+
+    let dir = scratch("progress-bar");
+    let a = file(&dir, "a.b", "+");
+    let b = file(&dir, "b.b", "[");
+    let output = bfstk(&["--progress-bar", &a, &b], b"");
+    assert!(stderr(&output).contains("progress: 1/2 files, 1 passed, 0 failed\n"));
+    assert!(stderr(&output).contains("progress: 2/2 files, 1 passed, 1 failed\n"));
+}
+
+#[test]
+fn max_program_bytes_refuses_large_sources() {
+    let dir = scratch("max-program-bytes");
+    let program = file(&dir, "prog.b", PRINT_A);
+    let output = bfstk(&["--max-program-bytes", "10", &program], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("over the limit of 10"));
+
+    let output = bfstk(&["--max-program-bytes", "100", &program], b"");
+    assert_eq!(output.stdout, b"A");
+}
+
+#[test]
+fn tape_snapshots_are_written_at_intervals() {
+    let dir = scratch("tape-snapshots");
+    let snapshots = dir.join("snapshots");
+    let args = ["--tape-snapshots", snapshots.to_str().unwrap(), "--snapshot-every", "2"];
+    let output = bfstk(&[&args[..], &["-e", "+>+>+>+"]].concat(), b"");
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_dir(snapshots).unwrap().count(), 3);
+}
+
+#[test]
+fn output_record_size_pads_each_write() {
+    let output = bfstk(&["--output-record-size", "4", "-e", PRINT_A], b"");
+    assert_eq!(output.stdout, b"A\0\0\0");
+}
+
+#[test]
+fn output_comes_before_a_read() {
+    let program = format!("{},.", PRINT_A);
+    let mut child = spawn(&["-e", &program]);
+    let mut first = [0u8; 1];
+    child.stdout.as_mut().unwrap().read_exact(&mut first).unwrap();
+    assert_eq!(&first, b"A");
+
+    child.stdin.take().unwrap().write_all(b"z").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.stdout, b"z");
+}
+
+#[test]
+fn count_backtracks_reports_back_edges() {
+    let output = bfstk(&["-r", "--count-backtracks", "-e", "+++[-]"], b"");
+    assert!(stderr(&output).contains("loops entered: 1\n"));
+    assert!(stderr(&output).contains("loop back-edges: 2\n"));
+}
+
+#[test]
+fn seed_from_stdin_hex_seeds_the_tape() {
+    let output = bfstk(&["--seed-from-stdin-hex", "-e", ".>."], b"4142\n");
+    assert_eq!(output.stdout, b"AB");
+}
+
+#[test]
+fn writes_are_raw_bytes() {
+    let output = bfstk(&["-e", &format!("{}.", "+".repeat(200))], b"");
+    assert_eq!(output.stdout, [200]);
+}
+
+#[test]
+fn flush_extension_writes_output_right_away() {
+    let program = format!("{}&+[]", PRINT_A);
+    let mut child = spawn(&["--flush-extension", "--timeout", "10", "-e", &program]);
+    let mut first = [0u8; 1];
+    child.stdout.as_mut().unwrap().read_exact(&mut first).unwrap();
+    assert_eq!(&first, b"A");
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn unmatched_brackets_give_line_and_col() {
+    let output = bfstk(&["-e", "+\n ["], b"");
+    assert!(stderr(&output).contains("unmatched '[' at line 2, col 2"));
+    let output = bfstk(&["-e", "+]"], b"");
+    assert!(stderr(&output).contains("unmatched ']' at line 1, col 2"));
+}
+
+#[test]
+fn trace_summary_ranks_loop_bodies_first() {
+    let output = bfstk(&["--emit=interpreter-trace-summary", "-e", "+++[>++<-]"], b"");
+    let hot: Vec<String> = stdout(&output).lines().skip(1).take(4).map(String::from).collect();
+    assert_eq!(hot, ["  1:5 '>': 3", "  1:6 '+': 3", "  1:8 '<': 3", "  1:9 '-': 3"]);
+}
+
+#[test]
+fn inline_programs_run() {
+    let output = bfstk(&["-e", PRINT_A, "--eval", ">>+++++++[<++++++++++>-]<---."], b"");
+    assert_eq!(output.stdout, b"AC");
+}
+
+#[test]
+fn max_steps_stops_an_empty_loop() {
+    let output = bfstk(&["--max-steps", "1000", "-e", "+[]"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("exceeded the limit of 1000 steps"));
+
+    // the run stops rather than fails, so the report still comes first
+    let output = bfstk(&["-r", "--max-steps", "1000", "-e", "+[]"], b"");
+    assert_eq!(output.status.code(), Some(2));
+    let report = stderr(&output);
+    assert!(
+        report.find("counter: 2").unwrap() < report.find("Error:").unwrap(),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn emit_c_with_runtime_prints_a_whole_program() {
+    let output = bfstk(&["--emit=c", "--with-runtime", "-e", "+[>.<-]"], b"");
+    let source = stdout(&output);
+    assert!(source.contains("int main(void) {"));
+    assert!(source.contains("static uint8_t tape[65536];"));
+    assert!(source.contains("uint8_t *p = tape + 32768;"));
+    assert!(source.contains("while (*p) {"));
+
+    // the tape follows the tape flags
+    let output = bfstk(
+        &["--emit=c", "--with-runtime", "--tape-size", "100", "-e", "+[>.<-]"],
+        b"",
+    );
+    assert!(stdout(&output).contains("static uint8_t tape[100];\n"));
+    assert!(stdout(&output).contains("uint8_t *p = tape;\n"));
+    let output = bfstk(&["--emit=c", "--with-runtime", "--wrap-tape", "8", "-e", "<+"], b"");
+    assert!(stdout(&output).contains("static uint8_t tape[8];\n"));
+    assert!(stdout(&output).contains("p = tape + (p - tape + 7) % 8;\n"));
+
+    // without the runtime only the statements are printed
+    let output = bfstk(&["--emit=c", "-e", "+[>.<-]"], b"");
+    assert!(!stdout(&output).contains("int main"));
+    assert!(stdout(&output).contains("\nwhile (*p) {\n"));
+}
+
+#[test]
+fn source_maps_point_generated_loops_at_their_brackets() {
+    let dir = scratch("source-map");
+    let map = dir.join("out.map").to_string_lossy().to_string();
+    for emit in ["--emit=c", "--emit=rust"] {
+        let output = bfstk(&[emit, "--source-map", &map, "-e", "+\n >[-<+>]"], b"");
+        assert!(output.status.success(), "{}", stderr(&output));
+        let code = stdout(&output);
+        let loop_line = code
+            .lines()
+            .position(|line| line.trim_start().starts_with("while"))
+            .unwrap()
+            + 1;
+        let map = std::fs::read_to_string(&map).unwrap();
+        assert!(map.lines().any(|line| line == format!("{} 2:3", loop_line)), "{}", map);
+    }
+}
+
+#[test]
+fn timeout_stops_an_endless_loop() {
+    let output = bfstk(&["--timeout", "0.2", "-e", "+[]"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("timed out after 0.2 seconds"));
+}
+
+#[test]
+fn warn_below_warns_once() {
+    let output = bfstk(&["--warn-below", "0", "-e", "<+><<"], b"");
+    assert_eq!(stderr(&output).matches("warning:").count(), 1);
+    assert!(stderr(&output).contains("pointer moved to -1, below 0"));
+}
+
+#[test]
+fn report_keeps_tape_extents() {
+    let program = format!("{}+{}+", "<".repeat(3000), ">".repeat(6000));
+    let output = bfstk(&["-r", "-e", &program], b"");
+    assert!(stderr(&output).contains("memory: 3000 3001\n"), "{}", stderr(&output));
+}
+
+#[test]
+fn pretty_indents_tokens_json() {
+    let plain = stdout(&bfstk(&["--emit=tokens-json", "-e", "+."], b""));
+    let pretty = stdout(&bfstk(&["--emit=tokens-json", "--pretty", "-e", "+."], b""));
+    assert_eq!(plain.trim_end().lines().count(), 1);
+    assert!(pretty.lines().count() > 2);
+    assert!(pretty.contains("\n    \"kind\": \"IncrementByte\""));
+    assert_eq!(parse_json(&pretty), parse_json(&plain));
+}
+
+#[test]
+fn report_histograms_executed_nodes() {
+    // --max-steps keeps the loop from being lowered
+    let output = bfstk(&["-r", "--max-steps", "1000", "-e", "++[>+.<-]"], b"");
+    let report = stderr(&output);
+    for line in ["Delta: 5 ", "Move: 4 ", "Write: 2 ", "loop iterations: 2 ", "Block: 1 "] {
+        assert!(report.contains(line), "{} in {}", line, report);
+    }
+}
+
+#[test]
+fn dump_ast_prints_the_parsed_tree() {
+    let output = bfstk(&["--dump-ast", "--no-run", "-e", "+++[>+<-]"], b"");
+    let expected = "Delta(3)\nBlock@3\n  Move(1)\n  Delta(1)\n  Move(-1)\n  Delta(-1)\n";
+    assert_eq!(stderr(&output), expected);
+}
+
+#[test]
+fn range_runs_a_fragment_against_a_seeded_tape() {
+    let output = bfstk(&["--range", "3:10", "--arg-cells", "3", "-d", "-e", "+++[>++<-]>"], b"");
+    assert!(stderr(&output).contains("right: [0, 6]"), "{}", stderr(&output));
+}
+
+#[test]
+fn selftest_passes() {
+    let output = bfstk(&["--selftest"], b"");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("selftest: 3 of 3 passed"));
+}
+
+#[test]
+fn emit_rust_keeps_the_loop_structure() {
+    let output = bfstk(&["--emit=rust", "-e", ",[.,]"], b"");
+    let source = stdout(&output);
+    assert!(source.contains("fn main() {"));
+    let body = source.split("while tape[p] != 0 {").nth(1).unwrap();
+    let read = body.find("stdin()").unwrap();
+    assert!(body.find("out.write_all(&[tape[p] as u8])").unwrap() < read);
+}
+
+#[test]
+fn lowering_keeps_instrumentation_per_instruction() {
+    let output = bfstk(&["--emit-profile=folded", "-e", "++[>++[-]<-]"], b"");
+    assert!(stderr(&output).contains("loop@2;loop@6 4"));
+
+    let output = bfstk(&["-r", "--count-backtracks", "-e", "++[-]"], b"");
+    assert!(stderr(&output).contains("loops entered: 1\n"));
+
+    // --max-steps never lowers loops, so both runs must see every step
+    for extra in [&[][..], &["--max-steps=100000"][..]] {
+        let args = [extra, &["--run-until-tape", "01", "-d", "-e", "+>+++<-"]].concat();
+        assert!(stderr(&bfstk(&args, b"")).contains("right: [1]\n"));
+
+        let args = [extra, &["--run-until-tape", "02", "-d", "-e", "+++++[-]"]].concat();
+        assert!(stderr(&bfstk(&args, b"")).contains("right: [2]\n"));
+
+        let args = [extra, &["--trace-cells", "0", "-e", "+++[-]"]].concat();
+        assert_eq!(stderr(&bfstk(&args, b"")).matches("watch: cell 0 changed").count(), 4);
+    }
+}
+
+#[test]
+fn opt_levels_pick_the_passes() {
+    let emit = |level| stdout(&bfstk(&[level, "--emit=c", "-e", "+[-]>[->+<]"], b""));
+    assert!(!emit("-O0").contains("*p = 0;"));
+    assert!(emit("-O1").contains("*p = 0;") && !emit("-O1").contains("p[1] +="));
+    assert!(emit("-O2").contains("p[1] +="));
+    assert_eq!(emit("-O2"), stdout(&bfstk(&["--emit=c", "-e", "+[-]>[->+<]"], b"")));
+
+    let output = bfstk(&["-O3", "-e", "+"], b"");
+    assert!(stderr(&output).contains("unknown optimization level '-O3'"));
+}
+
+#[test]
+fn bounded_tape_rejects_both_ends() {
+    let bounds = ["--no-negative", "--tape-size", "10"];
+    let output = bfstk(&[&bounds[..], &["-e", "<"]].concat(), b"");
+    assert!(stderr(&output).contains("left of cell 0"));
+
+    let program = ">".repeat(10);
+    let output = bfstk(&[&bounds[..], &["-e", &program]].concat(), b"");
+    assert!(stderr(&output).contains("past the end of a tape of 10 cells"));
+    assert!(stderr(&output).contains("cells 0 to 9"));
+
+    let output = bfstk(&["--tape-size", "2", "--arg-cells", "1", "2", "3", "-e", "+"], b"");
+    assert!(stderr(&output).contains("the tape seed sets 3 cells, but --tape-size only has 2"));
+}
+
+#[test]
+fn wrap_tape_wraps_both_ways() {
+    let output = bfstk(&["--wrap-tape", "5", "-e", "<+++>.>>>>."], b"");
+    assert_eq!(output.stdout, [0, 3]);
+
+    let output = bfstk(
+        &["--wrap-tape", "4", "--arg-cells", "1", "2", "3", "4", "-e", "<."],
+        b"",
+    );
+    assert_eq!(output.stdout, [4]);
+
+    let output = bfstk(
+        &["--wrap-tape", "4", "--arg-cells", "1", "2", "3", "4", "5", "-e", "+"],
+        b"",
+    );
+    assert!(!output.status.success());
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "http")]
+#![allow(clippy::needless_return)]
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::process::Command;
+
+/// serve answers a single request on a local port with the given response,
+/// returning the URL to fetch and the request that was received.
+fn serve(response: &'static str) -> (String, std::thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hi.b", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed before the request ended");
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response.as_bytes()).unwrap();
+        return String::from_utf8_lossy(&request).to_string();
+    });
+    return (url, handle);
+}
+
+#[test]
+fn runs_a_remote_program() {
+    let (url, handle) = serve("HTTP/1.0 200 OK\r\n\r\n++++++++[>+++++++++<-]>.+.");
+    let output = Command::new(env!("CARGO_BIN_EXE_bfstk")).arg(&url).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"HI");
+    assert!(handle.join().unwrap().starts_with("GET /hi.b HTTP/1.0\r\n"));
+}
+
+#[test]
+fn reports_an_error_status() {
+    let (url, handle) = serve("HTTP/1.0 404 Not Found\r\n\r\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_bfstk")).arg(&url).output().unwrap();
+    handle.join().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("404 Not Found"));
+}
+
+#[test]
+fn refuses_https() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bfstk"))
+        .arg("https://127.0.0.1/hi.b")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("TLS"));
+}